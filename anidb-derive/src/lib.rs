@@ -0,0 +1,536 @@
+//! Derive macro generating AniDB mask types from their response structs.
+//!
+//! This is an implementation detail of the `anidb` crate, the generated code
+//! refers to items in `anidb`'s private `mask` module.
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{
+    parse_macro_input,
+    spanned::Spanned,
+    Attribute,
+    Data,
+    DeriveInput,
+    Error,
+    Fields,
+    GenericArgument,
+    Ident,
+    Lit,
+    Meta,
+    NestedMeta,
+    Path,
+    PathArguments,
+    Type,
+};
+
+/// Generates a mask builder for a response struct.
+///
+/// ```ignore
+/// #[derive(Default, Mask)]
+/// #[mask(name = "AnimeMask", error = "FileRequestError")]
+/// pub struct AnimeMaskResponse {
+///     #[mask(byte = 1, bit = 7)]
+///     pub anime_total_episodes: Option<i32>,
+///     #[mask(byte = 2, bit = 4, decode_with = "decode_list")]
+///     pub other_name: Option<Vec<String>>,
+///     // fields without a `mask` attribute are filled in by hand
+///     pub relations: Option<Vec<(i32, RelationType)>>,
+/// }
+/// ```
+///
+/// Bytes are numbered from 1 as in the AniDB UDP API documentation. Fields
+/// with a mask bit must be declared in the order AniDB returns them, i.e.
+/// by byte and then from the highest bit to the lowest. When the mask size
+/// is given with `bytes = N`, every bit must either belong to a field or be
+/// listed in `reserved = "byte:bit, ..."`. The field enum is named after the
+/// mask unless given with `fields = "..."`.
+///
+/// This generates `AnimeMask` with a typed builder, `all()`/`none()`, set
+/// operations over an `AnimeMaskField` enum naming each field, a `Serialize`
+/// impl producing the hex encoded mask and a `decode_response` method reading
+/// the requested fields in order. The response struct gets `get`/`iter` for
+/// accessing fields by their enum value.
+#[proc_macro_derive(Mask, attributes(mask))]
+pub fn derive_mask(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+struct MaskAttrs {
+    name: Ident,
+    field_enum: Option<Ident>,
+    error: Path,
+    bytes: Option<u8>,
+    reserved: Vec<(u8, u8, proc_macro2::Span)>,
+}
+
+struct MaskField {
+    name: Ident,
+    ty: Type,
+    byte: u8,
+    bit: u8,
+    decode_with: Option<Path>,
+}
+
+fn mask_meta(attrs: &[Attribute]) -> Result<Option<Vec<NestedMeta>>, Error> {
+    let mut found = None;
+    for attr in attrs.iter().filter(|a| a.path.is_ident("mask")) {
+        if found.is_some() {
+            return Err(Error::new(attr.span(), "duplicate `mask` attribute"));
+        }
+        match attr.parse_meta()? {
+            Meta::List(list) => found = Some(list.nested.into_iter().collect()),
+            meta => return Err(Error::new(meta.span(), "expected `mask(...)`")),
+        }
+    }
+    Ok(found)
+}
+
+fn lit_str(lit: &Lit) -> Result<String, Error> {
+    match lit {
+        Lit::Str(s) => Ok(s.value()),
+        lit => Err(Error::new(lit.span(), "expected a string literal")),
+    }
+}
+
+fn lit_u8(lit: &Lit) -> Result<u8, Error> {
+    match lit {
+        Lit::Int(i) => i.base10_parse(),
+        lit => Err(Error::new(lit.span(), "expected an integer literal")),
+    }
+}
+
+type ByteBit = (Option<u8>, Option<u8>, Vec<NestedMeta>);
+
+/// Parses a list of reserved bits written as `"byte:bit, byte:bit"`.
+fn parse_reserved(lit: &Lit) -> Result<Vec<(u8, u8, proc_macro2::Span)>, Error> {
+    let span = lit.span();
+    let invalid = || Error::new(span, "expected reserved bits as \"byte:bit, byte:bit\"");
+    lit_str(lit)?
+        .split(',')
+        .map(|entry| {
+            let mut parts = entry.trim().splitn(2, ':');
+            let byte: u8 = parts.next().and_then(|b| b.trim().parse().ok()).ok_or_else(invalid)?;
+            let bit: u8 = parts.next().and_then(|b| b.trim().parse().ok()).ok_or_else(invalid)?;
+            if byte == 0 || bit > 7 {
+                return Err(invalid());
+            }
+            Ok((byte, bit, span))
+        })
+        .collect()
+}
+
+/// Pulls `byte` and `bit` out of a mask attribute, returning the remaining
+/// entries.
+fn parse_byte_bit(metas: impl IntoIterator<Item = NestedMeta>) -> Result<ByteBit, Error> {
+    let mut byte = None;
+    let mut bit = None;
+    let mut rest = Vec::new();
+    for meta in metas {
+        match meta {
+            NestedMeta::Meta(Meta::NameValue(nv)) if nv.path.is_ident("byte") => {
+                let value = lit_u8(&nv.lit)?;
+                if value == 0 {
+                    return Err(Error::new(nv.lit.span(), "mask bytes are numbered from 1"));
+                }
+                byte = Some(value);
+            },
+            NestedMeta::Meta(Meta::NameValue(nv)) if nv.path.is_ident("bit") => {
+                let value = lit_u8(&nv.lit)?;
+                if value > 7 {
+                    return Err(Error::new(nv.lit.span(), "mask bits range from 0 to 7"));
+                }
+                bit = Some(value);
+            },
+            meta => rest.push(meta),
+        }
+    }
+    Ok((byte, bit, rest))
+}
+
+fn parse_struct_attrs(input: &DeriveInput) -> Result<MaskAttrs, Error> {
+    let metas = mask_meta(&input.attrs)?.ok_or_else(|| Error::new(
+        input.ident.span(),
+        "missing `#[mask(name = \"...\", error = \"...\")]` attribute"
+    ))?;
+    let mut name = None;
+    let mut field_enum = None;
+    let mut error = None;
+    let mut bytes = None;
+    let mut reserved = Vec::new();
+    for meta in metas {
+        match meta {
+            NestedMeta::Meta(Meta::NameValue(nv)) if nv.path.is_ident("name") => {
+                name = Some(Ident::new(&lit_str(&nv.lit)?, nv.lit.span()));
+            },
+            NestedMeta::Meta(Meta::NameValue(nv)) if nv.path.is_ident("fields") => {
+                field_enum = Some(Ident::new(&lit_str(&nv.lit)?, nv.lit.span()));
+            },
+            NestedMeta::Meta(Meta::NameValue(nv)) if nv.path.is_ident("error") => {
+                error = Some(syn::parse_str(&lit_str(&nv.lit)?)?);
+            },
+            NestedMeta::Meta(Meta::NameValue(nv)) if nv.path.is_ident("bytes") => {
+                bytes = Some(lit_u8(&nv.lit)?);
+            },
+            NestedMeta::Meta(Meta::NameValue(nv)) if nv.path.is_ident("reserved") => {
+                reserved = parse_reserved(&nv.lit)?;
+            },
+            meta => return Err(Error::new(meta.span(), "unknown mask attribute")),
+        }
+    }
+    Ok(MaskAttrs {
+        name: name.ok_or_else(|| Error::new(input.ident.span(), "missing mask `name`"))?,
+        field_enum,
+        error: error.ok_or_else(|| Error::new(input.ident.span(), "missing mask `error`"))?,
+        bytes,
+        reserved,
+    })
+}
+
+fn option_inner(ty: &Type) -> Option<&Type> {
+    let path = match ty {
+        Type::Path(p) if p.qself.is_none() => &p.path,
+        _ => return None,
+    };
+    let segment = path.segments.last()?;
+    if segment.ident != "Option" {
+        return None;
+    }
+    match &segment.arguments {
+        PathArguments::AngleBracketed(args) if args.args.len() == 1 => {
+            match args.args.first() {
+                Some(GenericArgument::Type(inner)) => Some(inner),
+                _ => None,
+            }
+        },
+        _ => None,
+    }
+}
+
+fn parse_field(field: &syn::Field) -> Result<Option<MaskField>, Error> {
+    let metas = match mask_meta(&field.attrs)? {
+        Some(metas) => metas,
+        None => return Ok(None),
+    };
+    let name = field.ident.clone()
+        .ok_or_else(|| Error::new(field.span(), "mask fields must be named"))?;
+    let ty = option_inner(&field.ty)
+        .ok_or_else(|| Error::new(field.ty.span(), "mask fields must be `Option<T>`"))?
+        .clone();
+    let (byte, bit, rest) = parse_byte_bit(metas)?;
+    let mut decode_with = None;
+    for meta in rest {
+        match meta {
+            NestedMeta::Meta(Meta::NameValue(nv)) if nv.path.is_ident("decode_with") => {
+                decode_with = Some(syn::parse_str(&lit_str(&nv.lit)?)?);
+            },
+            meta => return Err(Error::new(meta.span(), "unknown mask attribute")),
+        }
+    }
+    Ok(Some(MaskField {
+        byte: byte.ok_or_else(|| Error::new(name.span(), "missing mask `byte`"))?,
+        bit: bit.ok_or_else(|| Error::new(name.span(), "missing mask `bit`"))?,
+        name,
+        ty,
+        decode_with,
+    }))
+}
+
+/// AniDB returns fields ordered by byte and then from the highest bit down,
+/// this is the position of a bit in that order.
+fn position(byte: u8, bit: u8) -> u16 {
+    u16::from(byte) * 8 + u16::from(7 - bit)
+}
+
+fn validate(attrs: &MaskAttrs, fields: &[MaskField]) -> Result<(), Error> {
+    for (i, field) in fields.iter().enumerate() {
+        for other in &fields[..i] {
+            if other.byte == field.byte && other.bit == field.bit {
+                return Err(Error::new(field.name.span(), format!(
+                    "byte {} bit {} is already used by `{}`",
+                    field.byte, field.bit, other.name
+                )));
+            }
+        }
+        if let Some(prev) = i.checked_sub(1).map(|i| &fields[i]) {
+            if position(prev.byte, prev.bit) > position(field.byte, field.bit) {
+                return Err(Error::new(field.name.span(), format!(
+                    "`{}` (byte {} bit {}) must be declared before `{}` (byte {} bit {})",
+                    field.name, field.byte, field.bit, prev.name, prev.byte, prev.bit
+                )));
+            }
+        }
+    }
+    for (i, &(byte, bit, span)) in attrs.reserved.iter().enumerate() {
+        let taken = fields.iter().any(|f| f.byte == byte && f.bit == bit)
+            || attrs.reserved[..i].iter().any(|r| r.0 == byte && r.1 == bit);
+        if taken {
+            return Err(Error::new(span, format!(
+                "byte {} bit {} is declared twice", byte, bit
+            )));
+        }
+    }
+    // with an explicit size every bit of the documented table has to be
+    // accounted for, either by a field or as reserved
+    if let Some(bytes) = attrs.bytes {
+        let beyond = fields.iter().map(|f| (f.byte, f.name.span()))
+            .chain(attrs.reserved.iter().map(|r| (r.0, r.2)))
+            .find(|(byte, _)| *byte > bytes);
+        if let Some((byte, span)) = beyond {
+            return Err(Error::new(span, format!(
+                "byte {} is beyond the mask size of {} bytes", byte, bytes
+            )));
+        }
+        for byte in 1..=bytes {
+            for bit in (0..8).rev() {
+                let known = fields.iter().any(|f| f.byte == byte && f.bit == bit)
+                    || attrs.reserved.iter().any(|r| r.0 == byte && r.1 == bit);
+                if !known {
+                    return Err(Error::new(proc_macro2::Span::call_site(), format!(
+                        "byte {} bit {} is neither a field nor reserved", byte, bit
+                    )));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn camel_case(name: &str) -> String {
+    name.split('_')
+        .map(|part| {
+            let mut chars = part.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
+fn expand(input: DeriveInput) -> Result<TokenStream2, Error> {
+    let attrs = parse_struct_attrs(&input)?;
+    let named = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(named) => &named.named,
+            _ => return Err(Error::new(input.ident.span(), "Mask requires named fields")),
+        },
+        _ => return Err(Error::new(input.ident.span(), "Mask can only be derived for structs")),
+    };
+    let mut fields = Vec::new();
+    for field in named {
+        if let Some(field) = parse_field(field)? {
+            fields.push(field);
+        }
+    }
+    validate(&attrs, &fields)?;
+
+    let vis = &input.vis;
+    let resp = &input.ident;
+    let mask = &attrs.name;
+    let error = &attrs.error;
+    let byte_count = attrs.bytes.map(usize::from).unwrap_or_else(|| {
+        fields.iter().map(|f| f.byte as usize).max().unwrap_or(0)
+    });
+    let field_enum = attrs.field_enum.clone()
+        .unwrap_or_else(|| Ident::new(&format!("{}Field", mask), mask.span()));
+    let names: Vec<&Ident> = fields.iter().map(|f| &f.name).collect();
+    let name_strs: Vec<String> = fields.iter().map(|f| f.name.to_string()).collect();
+    let variants: Vec<Ident> = fields.iter()
+        .map(|f| Ident::new(&camel_case(&f.name.to_string()), f.name.span()))
+        .collect();
+    let byte_indices: Vec<usize> = fields.iter().map(|f| f.byte as usize - 1).collect();
+    let bits: Vec<u8> = fields.iter().map(|f| f.bit).collect();
+    let decoders: Vec<TokenStream2> = fields.iter().map(|f| {
+        let ty = &f.ty;
+        match &f.decode_with {
+            Some(path) => quote! {
+                let value: #ty = #path(input)?;
+            },
+            None => quote! {
+                let value = <#ty as crate::mask::FieldDecoder>::decode_field(input)?;
+            },
+        }
+    }).collect();
+
+    Ok(quote! {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        #[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+        #vis enum #field_enum {
+            #(
+                #[cfg_attr(feature = "serde", serde(rename = #name_strs))]
+                #variants,
+            )*
+        }
+
+        impl #field_enum {
+            pub const ALL: &'static [#field_enum] = &[#(#field_enum::#variants,)*];
+
+            pub fn name(&self) -> &'static str {
+                match self {
+                    #(#field_enum::#variants => #name_strs,)*
+                }
+            }
+        }
+
+        impl ::std::fmt::Display for #field_enum {
+            fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+                f.write_str(self.name())
+            }
+        }
+
+        impl ::std::str::FromStr for #field_enum {
+            type Err = crate::AniDbError;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                match s {
+                    #(#name_strs => Ok(#field_enum::#variants),)*
+                    _ => Err(crate::AniDbError::DecodeError(format!(
+                        "unknown {} field: {}", stringify!(#mask), s
+                    ))),
+                }
+            }
+        }
+
+        #[derive(Debug, Clone, Default, PartialEq, Eq, Hash, ::typed_builder::TypedBuilder)]
+        #vis struct #mask {
+            #(
+                #[builder(default)]
+                #names: bool,
+            )*
+        }
+
+        impl #mask {
+            pub fn all() -> #mask {
+                #mask {
+                    #(#names: true,)*
+                }
+            }
+
+            pub fn none() -> #mask {
+                #mask {
+                    #(#names: false,)*
+                }
+            }
+
+            pub fn from_fields<I>(fields: I) -> #mask
+            where I: IntoIterator<Item = #field_enum> {
+                fields.into_iter().fold(#mask::none(), |mask, field| mask.with(field))
+            }
+
+            pub fn contains(&self, field: #field_enum) -> bool {
+                match field {
+                    #(#field_enum::#variants => self.#names,)*
+                }
+            }
+
+            pub fn with(mut self, field: #field_enum) -> #mask {
+                match field {
+                    #(#field_enum::#variants => self.#names = true,)*
+                }
+                self
+            }
+
+            pub fn without(mut self, field: #field_enum) -> #mask {
+                match field {
+                    #(#field_enum::#variants => self.#names = false,)*
+                }
+                self
+            }
+
+            pub fn union(&self, other: &#mask) -> #mask {
+                #mask {
+                    #(#names: self.#names || other.#names,)*
+                }
+            }
+
+            pub fn intersection(&self, other: &#mask) -> #mask {
+                #mask {
+                    #(#names: self.#names && other.#names,)*
+                }
+            }
+
+            pub fn is_superset(&self, other: &#mask) -> bool {
+                #(( self.#names || !other.#names ) &&)* true
+            }
+
+            pub fn is_empty(&self) -> bool {
+                #(!self.#names &&)* true
+            }
+
+            /// The selected fields, in the order AniDB returns them.
+            pub fn fields(&self) -> impl Iterator<Item = #field_enum> + '_ {
+                #field_enum::ALL.iter().copied().filter(move |field| self.contains(*field))
+            }
+
+            /// Builds a mask from its raw bytes. Reserved bits are ignored so
+            /// they are never requested and can't shift the decoded fields.
+            pub fn from_bytes(bytes: &[u8]) -> Result<#mask, crate::AniDbError> {
+                if bytes.len() != #byte_count {
+                    return Err(crate::AniDbError::DecodeError(format!(
+                        "expected a {} byte mask, got {} bytes", #byte_count, bytes.len()
+                    )));
+                }
+                Ok(#mask {
+                    #(#names: bytes[#byte_indices] & (1 << #bits) != 0,)*
+                })
+            }
+
+            pub fn to_bytes(&self) -> [u8; #byte_count] {
+                let mut bytes = [0u8; #byte_count];
+                #(
+                    if self.#names {
+                        bytes[#byte_indices] |= 1 << #bits;
+                    }
+                )*
+                bytes
+            }
+
+            fn decode_response<'a>(
+                &self,
+                field_iter: &mut impl Iterator<Item = &'a str>
+            ) -> Result<#resp, #error> {
+                let mut resp = #resp::default();
+                #(
+                    if self.#names {
+                        let input = field_iter.next().ok_or_else(|| crate::AniDbError::DecodeError(
+                            String::from("Missing expected field")
+                        ))?;
+                        #decoders
+                        resp.#names = Some(value);
+                    }
+                )*
+                Ok(resp)
+            }
+        }
+
+        impl #resp {
+            pub fn get(&self, field: #field_enum) -> Option<crate::mask::FieldValue> {
+                match field {
+                    #(
+                        #field_enum::#variants => self.#names.as_ref()
+                            .map(crate::mask::ToFieldValue::to_field_value),
+                    )*
+                }
+            }
+
+            /// The fields present in this response, in mask order.
+            pub fn iter(&self) -> impl Iterator<Item = (#field_enum, crate::mask::FieldValue)> + '_ {
+                #field_enum::ALL.iter()
+                    .filter_map(move |field| self.get(*field).map(|value| (*field, value)))
+            }
+        }
+
+        impl ::serde::Serialize for #mask {
+            fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+            where S: ::serde::Serializer {
+                serializer.serialize_str(&::hex::encode(self.to_bytes()))
+            }
+        }
+    })
+}
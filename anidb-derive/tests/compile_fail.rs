@@ -0,0 +1,7 @@
+// Every error the derive reports, with the expected messages next to each
+// case under tests/ui.
+#[test]
+fn compile_errors() {
+    let cases = trybuild::TestCases::new();
+    cases.compile_fail("tests/ui/*.rs");
+}
@@ -0,0 +1,10 @@
+use anidb_derive::Mask;
+
+#[derive(Mask)]
+#[mask(name = "TestMask", error = "TestError")]
+struct Response {
+    #[mask(byte = 1, bit = 8)]
+    a: Option<i32>,
+}
+
+fn main() {}
@@ -0,0 +1,11 @@
+use anidb_derive::Mask;
+
+#[derive(Mask)]
+#[mask(name = "TestMask", error = "TestError")]
+#[mask(name = "TestMask", error = "TestError")]
+struct Response {
+    #[mask(byte = 1, bit = 7)]
+    a: Option<i32>,
+}
+
+fn main() {}
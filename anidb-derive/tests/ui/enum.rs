@@ -0,0 +1,9 @@
+use anidb_derive::Mask;
+
+#[derive(Mask)]
+#[mask(name = "TestMask", error = "TestError")]
+enum Response {
+    A,
+}
+
+fn main() {}
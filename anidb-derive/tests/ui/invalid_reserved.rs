@@ -0,0 +1,10 @@
+use anidb_derive::Mask;
+
+#[derive(Mask)]
+#[mask(name = "TestMask", error = "TestError", reserved = "1-6")]
+struct Response {
+    #[mask(byte = 1, bit = 7)]
+    a: Option<i32>,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use anidb_derive::Mask;
+
+#[derive(Mask)]
+struct Response {
+    #[mask(byte = 1, bit = 7)]
+    a: Option<i32>,
+}
+
+fn main() {}
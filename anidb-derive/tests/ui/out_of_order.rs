@@ -0,0 +1,12 @@
+use anidb_derive::Mask;
+
+#[derive(Mask)]
+#[mask(name = "TestMask", error = "TestError")]
+struct Response {
+    #[mask(byte = 1, bit = 6)]
+    a: Option<i32>,
+    #[mask(byte = 1, bit = 7)]
+    b: Option<i32>,
+}
+
+fn main() {}
@@ -0,0 +1,66 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+use anidb::crc::{crc_hash_reader, crc_hash_with_control};
+use anidb::ed2k::{ed2k_hash_reader, ed2k_hash_with_control, Ed2kVariant};
+use anidb::hash::{hash_file_with_control, hash_reader, DigestSelection};
+use anidb::progress::HashControl;
+
+// The `*_reader` functions always do buffered reads, the path based ones map
+// the file when allowed to, which needs the `mmap` feature this bench requires.
+
+// SAFETY: the generated file is only removed once every run is done
+fn mapped<'a>() -> HashControl<'a> {
+    unsafe { HashControl::new().allow_mmap() }
+}
+
+const SIZE: usize = 256 * 1024 * 1024;
+
+fn generate_file() -> PathBuf {
+    let path = std::env::temp_dir().join(format!("anidb-bench-{}", std::process::id()));
+    let mut writer = BufWriter::new(File::create(&path).unwrap());
+    let block: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+    for _ in 0..SIZE / block.len() {
+        writer.write_all(&block).unwrap();
+    }
+    writer.flush().unwrap();
+    path
+}
+
+fn hashing(c: &mut Criterion) {
+    let path = generate_file();
+    let open = || File::open(&path).unwrap();
+
+    let mut group = c.benchmark_group("hashing");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(SIZE as u64));
+
+    group.bench_function("ed2k/buffered", |b| b.iter(|| {
+        ed2k_hash_reader(open(), Ed2kVariant::Red, HashControl::new()).unwrap()
+    }));
+    group.bench_function("ed2k/mmap", |b| b.iter(|| {
+        ed2k_hash_with_control(&path, Ed2kVariant::Red, mapped()).unwrap()
+    }));
+
+    group.bench_function("crc/buffered", |b| b.iter(|| {
+        crc_hash_reader(open(), HashControl::new()).unwrap()
+    }));
+    group.bench_function("crc/mmap", |b| b.iter(|| {
+        crc_hash_with_control(&path, mapped()).unwrap()
+    }));
+
+    group.bench_function("all/buffered", |b| b.iter(|| {
+        hash_reader(open(), DigestSelection::all(), Ed2kVariant::Red, HashControl::new()).unwrap()
+    }));
+    group.bench_function("all/mmap", |b| b.iter(|| {
+        hash_file_with_control(&path, DigestSelection::all(), Ed2kVariant::Red, mapped()).unwrap()
+    }));
+
+    group.finish();
+    fs::remove_file(path).unwrap();
+}
+
+criterion_group!(benches, hashing);
+criterion_main!(benches);
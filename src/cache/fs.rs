@@ -0,0 +1,362 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use super::{AniDbCache, CacheKey, CachedReply, ReplyClass, Ttls};
+
+#[derive(Debug, thiserror::Error)]
+pub enum FsCacheError {
+    #[error("IoError: {0}")]
+    Io(#[from] io::Error),
+    #[error("Serialization Error: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+#[derive(Serialize, Deserialize)]
+struct CacheFile {
+    command: String,
+    /// `CacheKey::target`, to tell apart hash collisions.
+    args: String,
+    #[serde(default)]
+    masks: String,
+    code: String,
+    reply: String,
+    data: String,
+    /// Milliseconds since the unix epoch.
+    fetched_at: u64,
+    /// Milliseconds a negative reply may be kept.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    negative_ttl: Option<u64>,
+}
+
+// 64 bit FNV-1a, file names have to stay the same across builds and
+// platforms, which the std hashers do not promise
+fn fnv1a(input: &str) -> u64 {
+    input.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+// Temporary files this old can only be left behind by a crashed writer.
+const STALE_TMP_AGE: Duration = Duration::from_secs(60 * 60);
+
+// Tells the file at a path apart from one renamed over it since.
+#[derive(Debug, PartialEq, Eq)]
+struct FileStamp {
+    modified: SystemTime,
+    len: u64,
+    inode: u64,
+}
+
+#[cfg(unix)]
+fn inode(metadata: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.ino()
+}
+
+#[cfg(not(unix))]
+fn inode(_metadata: &fs::Metadata) -> u64 {
+    0
+}
+
+fn stamp(path: &Path) -> io::Result<Option<FileStamp>> {
+    match fs::metadata(path) {
+        Ok(metadata) => Ok(Some(FileStamp {
+            modified: metadata.modified()?,
+            len: metadata.len(),
+            inode: inode(&metadata),
+        })),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Cache keeping each reply as a JSON file in a directory, under
+/// `<command>/<hash of the key target>.json`. Files are written to a temporary
+/// name and renamed into place, so several processes can share the directory.
+///
+/// Replies expire one day after they were fetched unless configured
+/// otherwise, checked when they are read. Expired files are only deleted by
+/// `prune`.
+pub struct FsCache {
+    dir: PathBuf,
+    ttls: Ttls,
+    tmp_counter: AtomicU64,
+}
+
+impl FsCache {
+    /// Uses `dir` as cache directory, creating it if needed.
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<FsCache, FsCacheError> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        Ok(FsCache {
+            dir,
+            ttls: Ttls::default(),
+            tmp_counter: AtomicU64::new(0),
+        })
+    }
+
+    /// TTL for commands without their own, `None` never expires.
+    pub fn with_default_ttl(mut self, ttl: Option<Duration>) -> FsCache {
+        self.ttls.set_default(ttl);
+        self
+    }
+
+    /// TTL for a single command, e.g. long for `FILE` and short for `MYLIST`.
+    pub fn with_ttl(mut self, command: &str, ttl: Option<Duration>) -> FsCache {
+        self.ttls.set(command, ttl);
+        self
+    }
+
+    fn path(&self, command: &str, target: &str) -> PathBuf {
+        self.dir
+            .join(command.to_lowercase())
+            .join(format!("{:016x}.json", fnv1a(target)))
+    }
+
+    fn is_expired(&self, cached: &CacheFile, now: u64) -> bool {
+        let class = match cached.negative_ttl {
+            Some(ttl) => ReplyClass::Negative { ttl: Duration::from_millis(ttl) },
+            None => ReplyClass::Success,
+        };
+        match self.ttls.for_reply(&cached.command, class) {
+            Some(ttl) => now.saturating_sub(cached.fetched_at) >= ttl.as_millis() as u64,
+            None => false,
+        }
+    }
+
+    // Unreadable files are treated like missing ones, they can only come
+    // from a crash or outside tampering and are overwritten on the next store.
+    fn read(path: &Path) -> Result<Option<CacheFile>, FsCacheError> {
+        match fs::read(path) {
+            Ok(contents) => Ok(serde_json::from_slice(&contents).ok()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Deletes every expired or unreadable reply, along with temporary files
+    /// left behind by writers that crashed, returning how many files were
+    /// removed.
+    pub fn prune(&self) -> Result<usize, FsCacheError> {
+        let now = now_millis();
+        let stale_tmp = SystemTime::now() - STALE_TMP_AGE;
+        let mut removed = 0;
+        for command_dir in fs::read_dir(&self.dir)? {
+            let command_dir = command_dir?.path();
+            if !command_dir.is_dir() {
+                continue;
+            }
+            for file in fs::read_dir(&command_dir)? {
+                let path = file?.path();
+                let before = match stamp(&path)? {
+                    Some(before) => before,
+                    None => continue,
+                };
+                let expired = match path.extension().and_then(|ext| ext.to_str()) {
+                    Some("tmp") => before.modified < stale_tmp,
+                    Some("json") => match FsCache::read(&path)? {
+                        Some(cached) => self.is_expired(&cached, now),
+                        None => true,
+                    },
+                    _ => false,
+                };
+                // a store may have renamed a fresh reply over it meanwhile
+                if expired && stamp(&path)?.as_ref() == Some(&before) {
+                    remove_if_exists(&path)?;
+                    removed += 1;
+                }
+            }
+        }
+        Ok(removed)
+    }
+}
+
+// Filesystem calls run inline, cache files are small.
+#[async_trait]
+impl AniDbCache for FsCache {
+    type Error = FsCacheError;
+
+    async fn get(
+        &self,
+        key: &CacheKey
+    ) -> Result<Option<CachedReply>, Self::Error> {
+        let target = key.target();
+        let path = self.path(key.command(), &target);
+        let cached = match FsCache::read(&path)? {
+            // a different command or args means a hash collision
+            Some(cached) if cached.command == key.command() && cached.args == target => cached,
+            _ => return Ok(None),
+        };
+        // left for `prune`, deleting it here could remove a fresh reply
+        // another process just renamed over it
+        if self.is_expired(&cached, now_millis()) {
+            return Ok(None);
+        }
+        Ok(Some(CachedReply {
+            masks: cached.masks,
+            code: cached.code,
+            reply: cached.reply,
+            data: cached.data,
+        }))
+    }
+
+    async fn store(
+        &self,
+        key: &CacheKey,
+        code: &str,
+        reply: &str,
+        data: &str,
+        class: ReplyClass
+    ) -> Result<(), Self::Error> {
+        let target = key.target();
+        let path = self.path(key.command(), &target);
+        let dir = path.parent().expect("cache paths have a command directory");
+        fs::create_dir_all(dir)?;
+        let contents = serde_json::to_vec(&CacheFile {
+            command: key.command().to_string(),
+            args: target,
+            masks: key.masks(),
+            code: code.to_string(),
+            reply: reply.to_string(),
+            data: data.to_string(),
+            fetched_at: now_millis(),
+            negative_ttl: match class {
+                ReplyClass::Success => None,
+                ReplyClass::Negative { ttl } => Some(ttl.as_millis() as u64),
+            },
+        })?;
+        // unique per process and call, so concurrent writers never share a
+        // temporary file, the last rename wins
+        let tmp = dir.join(format!(
+            ".{}.{}.tmp",
+            std::process::id(),
+            self.tmp_counter.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&tmp, contents)?;
+        if let Err(e) = fs::rename(&tmp, &path) {
+            let _ = fs::remove_file(&tmp);
+            return Err(e.into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(command: &str, args: &str) -> CacheKey {
+        CacheKey::new(command, args, &["fmask"])
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("anidb-fs-cache-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn stable_file_names() {
+        assert_eq!(fnv1a(""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a("a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(fnv1a("foobar"), 0x8594_4171_f739_67e8);
+    }
+
+    #[tokio::test]
+    async fn store_and_get() {
+        let dir = temp_dir("roundtrip");
+        let cache = FsCache::open(&dir).unwrap();
+        cache.store(&key("FILE", "fid=1&fmask=7800000000"), "220", "FILE", "1|2", ReplyClass::Success).await.unwrap();
+        assert_eq!(
+            cache.get(&key("FILE", "fid=1&fmask=7800000000")).await.unwrap(),
+            Some(CachedReply {
+                masks: String::from("fmask=7800000000"),
+                code: String::from("220"),
+                reply: String::from("FILE"),
+                data: String::from("1|2"),
+            })
+        );
+        assert_eq!(cache.get(&key("FILE", "fid=2")).await.unwrap(), None);
+        assert_eq!(cache.get(&key("ANIME", "fid=1&fmask=7800000000")).await.unwrap(), None);
+
+        // another instance on the same directory sees it, and overwrites
+        let other = FsCache::open(&dir).unwrap();
+        other.store(&key("FILE", "fid=1&fmask=7800000000"), "220", "FILE", "3|4", ReplyClass::Success).await.unwrap();
+        // stored per target, a narrower mask finds the same reply
+        let cached = cache.get(&key("FILE", "fid=1&fmask=0800000000")).await.unwrap().unwrap();
+        assert_eq!(cached.data, "3|4");
+
+        // no temporary files left behind
+        let files: Vec<_> = fs::read_dir(dir.join("file")).unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(files.len(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn stores_change_the_stamp() {
+        let dir = temp_dir("stamp");
+        let cache = FsCache::open(&dir).unwrap();
+        let path = cache.path("FILE", "fid=1");
+        assert_eq!(stamp(&path).unwrap(), None);
+        cache.store(&key("FILE", "fid=1"), "220", "FILE", "1|2", ReplyClass::Success).await.unwrap();
+        let before = stamp(&path).unwrap().unwrap();
+        assert_eq!(stamp(&path).unwrap().as_ref(), Some(&before));
+        cache.store(&key("FILE", "fid=1"), "220", "FILE", "1|2", ReplyClass::Success).await.unwrap();
+        assert_ne!(stamp(&path).unwrap().as_ref(), Some(&before));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn expires_and_prunes() {
+        let dir = temp_dir("expiry");
+        let cache = FsCache::open(&dir).unwrap()
+            .with_default_ttl(None)
+            .with_ttl("MYLIST", Some(Duration::from_millis(20)));
+        for args in ["lid=1", "lid=2"] {
+            cache.store(&key("MYLIST", args), "221", "MYLIST", "", ReplyClass::Success).await.unwrap();
+        }
+        cache.store(&key("FILE", "fid=1"), "220", "FILE", "", ReplyClass::Success).await.unwrap();
+        let negative = ReplyClass::Negative { ttl: Duration::from_millis(20) };
+        cache.store(&key("FILE", "fid=2"), "320", "NO SUCH FILE", "", negative).await.unwrap();
+        fs::write(dir.join("file").join("garbage.json"), b"{").unwrap();
+        // temporary files of a crashed writer, only old ones are stale
+        let stale = dir.join("file").join(".1.0.tmp");
+        fs::write(&stale, b"{").unwrap();
+        fs::File::options().write(true).open(&stale).unwrap()
+            .set_modified(SystemTime::now() - 2 * STALE_TMP_AGE)
+            .unwrap();
+        let recent = dir.join("file").join(".1.1.tmp");
+        fs::write(&recent, b"{").unwrap();
+        tokio::time::sleep(Duration::from_millis(40)).await;
+
+        assert_eq!(cache.get(&key("MYLIST", "lid=1")).await.unwrap(), None);
+        assert!(cache.path("MYLIST", "lid=1").exists());
+        assert!(cache.get(&key("FILE", "fid=1")).await.unwrap().is_some());
+        // lid=1, lid=2, fid=2, the unreadable file and the stale temporary one
+        assert_eq!(cache.prune().unwrap(), 5);
+        assert!(!stale.exists());
+        assert!(recent.exists());
+        assert!(cache.get(&key("FILE", "fid=1")).await.unwrap().is_some());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+// the client appends these per send, they never change the reply
+const SESSION_PARAMS: &[&str] = &["s", "tag"];
+
+/// Canonical identity of a request in the cache: the command and its
+/// arguments sorted by name, without session and tag. Field masks are kept
+/// apart from the other arguments, replies are stored per target and a reply
+/// fetched with wider masks can answer a request for fewer fields.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    command: String,
+    params: BTreeMap<String, String>,
+    masks: BTreeMap<String, String>,
+}
+
+fn parse_args(args: &str) -> Vec<(String, String)> {
+    let mut pairs: Vec<String> = Vec::new();
+    for part in args.split('&').filter(|part| !part.is_empty()) {
+        match pairs.last_mut() {
+            // a `&` inside a value is sent as `&amp;`
+            Some(last) if part.starts_with("amp;") => {
+                last.push('&');
+                last.push_str(part);
+            }
+            _ => pairs.push(part.to_string()),
+        }
+    }
+    pairs.into_iter()
+        .map(|pair| match pair.split_once('=') {
+            Some((name, value)) => (name.to_string(), value.to_string()),
+            None => (pair, String::new()),
+        })
+        .collect()
+}
+
+fn join(params: &BTreeMap<String, String>) -> String {
+    params.iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+impl CacheKey {
+    /// Builds the key for `command` sent with the encoded `args`, the
+    /// arguments named in `mask_params` being hex encoded field masks.
+    pub fn new(command: &str, args: &str, mask_params: &[&str]) -> CacheKey {
+        let mut params = BTreeMap::new();
+        let mut masks = BTreeMap::new();
+        for (name, value) in parse_args(args) {
+            if SESSION_PARAMS.contains(&name.as_str()) {
+                continue;
+            }
+            if mask_params.contains(&name.as_str()) {
+                masks.insert(name, value.to_lowercase());
+            } else {
+                params.insert(name, value);
+            }
+        }
+        CacheKey {
+            command: command.to_string(),
+            params,
+            masks,
+        }
+    }
+
+    pub fn command(&self) -> &str {
+        &self.command
+    }
+
+    /// The arguments besides the masks, what replies are stored under.
+    pub fn target(&self) -> String {
+        join(&self.params)
+    }
+
+    /// The masks, e.g. `amask=00000000&fmask=7800000000`, empty without any.
+    pub fn masks(&self) -> String {
+        join(&self.masks)
+    }
+
+    /// The hex encoded mask sent as `param`.
+    pub fn mask(&self, param: &str) -> Option<&str> {
+        self.masks.get(param).map(String::as_str)
+    }
+
+    /// The same target with the masks a cached reply was fetched with, as
+    /// returned by `masks`.
+    pub fn with_masks(&self, masks: &str) -> CacheKey {
+        CacheKey {
+            command: self.command.clone(),
+            params: self.params.clone(),
+            masks: parse_args(masks).into_iter()
+                .map(|(name, value)| (name, value.to_lowercase()))
+                .collect(),
+        }
+    }
+}
+
+impl fmt::Display for CacheKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.command, self.target())?;
+        if !self.masks.is_empty() {
+            write!(f, "&{}", self.masks())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MASKS: &[&str] = &["fmask", "amask"];
+
+    #[test]
+    fn canonical() {
+        let key = CacheKey::new("FILE", "size=12&ed2k=abc&fmask=78000000FF&amask=00000000", MASKS);
+        let other = CacheKey::new(
+            "FILE",
+            "amask=00000000&ed2k=abc&fmask=78000000ff&size=12&s=xyz&tag=t1",
+            MASKS
+        );
+        assert_eq!(key, other);
+        assert_eq!(key.target(), "ed2k=abc&size=12");
+        assert_eq!(key.masks(), "amask=00000000&fmask=78000000ff");
+        assert_eq!(key.mask("fmask"), Some("78000000ff"));
+        assert_eq!(key.with_masks(&key.masks()), key);
+        assert_eq!(key.to_string(), "FILE ed2k=abc&size=12&amask=00000000&fmask=78000000ff");
+    }
+
+    #[test]
+    fn escaped_ampersands() {
+        let key = CacheKey::new("ANIME", "aname=Tom &amp; Jerry&amask=80", &["amask"]);
+        assert_eq!(key.target(), "aname=Tom &amp; Jerry");
+        assert_eq!(key.masks(), "amask=80");
+    }
+}
@@ -0,0 +1,235 @@
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+
+use super::{AniDbCache, CacheKey, CachedReply, ReplyClass, Ttls};
+
+const DEFAULT_MAX_ENTRIES: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Entries dropped to stay within the maximum entry count, expired
+    /// entries are not counted.
+    pub evictions: u64,
+}
+
+type Key = (String, String);
+
+struct Entry {
+    reply: CachedReply,
+    expires: Option<Instant>,
+    // position in `State::order`, bumped on every hit
+    tick: u64,
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<Key, Entry>,
+    order: BTreeMap<u64, Key>,
+    next_tick: u64,
+    stats: CacheStats,
+}
+
+impl State {
+    fn touch(&mut self, key: &Key) {
+        let tick = self.next_tick;
+        self.next_tick += 1;
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.order.remove(&entry.tick);
+            entry.tick = tick;
+            self.order.insert(tick, key.clone());
+        }
+    }
+
+    fn remove(&mut self, key: &Key) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.tick);
+        }
+    }
+}
+
+/// Cache keeping replies in memory, with a time to live per command and a
+/// maximum entry count after which the least recently used entry is evicted.
+///
+/// Replies live for a day unless configured otherwise, a TTL of `None` keeps
+/// them until evicted.
+pub struct InMemoryCache {
+    max_entries: usize,
+    ttls: Ttls,
+    state: Mutex<State>,
+}
+
+impl Default for InMemoryCache {
+    fn default() -> InMemoryCache {
+        InMemoryCache::new(DEFAULT_MAX_ENTRIES)
+    }
+}
+
+impl InMemoryCache {
+    pub fn new(max_entries: usize) -> InMemoryCache {
+        InMemoryCache {
+            max_entries,
+            ttls: Ttls::default(),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// TTL for commands without their own.
+    pub fn with_default_ttl(mut self, ttl: Option<Duration>) -> InMemoryCache {
+        self.ttls.set_default(ttl);
+        self
+    }
+
+    /// TTL for a single command, e.g. long for `FILE` and short for `MYLIST`.
+    pub fn with_ttl(mut self, command: &str, ttl: Option<Duration>) -> InMemoryCache {
+        self.ttls.set(command, ttl);
+        self
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.state.lock().unwrap().stats
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.entries.clear();
+        state.order.clear();
+    }
+}
+
+#[async_trait]
+impl AniDbCache for InMemoryCache {
+    type Error = Infallible;
+
+    async fn get(
+        &self,
+        key: &CacheKey
+    ) -> Result<Option<CachedReply>, Self::Error> {
+        let key = (key.command().to_string(), key.target());
+        let mut state = self.state.lock().unwrap();
+        let expired = match state.entries.get(&key) {
+            Some(entry) => entry.expires.is_some_and(|expires| expires <= Instant::now()),
+            None => {
+                state.stats.misses += 1;
+                return Ok(None);
+            }
+        };
+        if expired {
+            state.remove(&key);
+            state.stats.misses += 1;
+            return Ok(None);
+        }
+        state.touch(&key);
+        state.stats.hits += 1;
+        Ok(state.entries.get(&key).map(|entry| entry.reply.clone()))
+    }
+
+    async fn store(
+        &self,
+        key: &CacheKey,
+        code: &str,
+        reply: &str,
+        data: &str,
+        class: ReplyClass
+    ) -> Result<(), Self::Error> {
+        if self.max_entries == 0 {
+            return Ok(());
+        }
+        let masks = key.masks();
+        let expires = self.ttls.for_reply(key.command(), class).map(|ttl| Instant::now() + ttl);
+        let key = (key.command().to_string(), key.target());
+        let mut state = self.state.lock().unwrap();
+        state.remove(&key);
+        while state.entries.len() >= self.max_entries {
+            let oldest = match state.order.values().next() {
+                Some(oldest) => oldest.clone(),
+                None => break,
+            };
+            state.remove(&oldest);
+            state.stats.evictions += 1;
+        }
+        let tick = state.next_tick;
+        state.next_tick += 1;
+        state.order.insert(tick, key.clone());
+        state.entries.insert(key, Entry {
+            reply: CachedReply {
+                masks,
+                code: code.to_string(),
+                reply: reply.to_string(),
+                data: data.to_string(),
+            },
+            expires,
+            tick,
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn store(cache: &InMemoryCache, command: &str, args: &str) {
+        let key = CacheKey::new(command, args, &[]);
+        cache.store(&key, "220", "FILE", args, ReplyClass::Success).await.unwrap();
+    }
+
+    async fn get(cache: &InMemoryCache, command: &str, args: &str) -> Option<String> {
+        let key = CacheKey::new(command, args, &[]);
+        cache.get(&key).await.unwrap().map(|cached| cached.data)
+    }
+
+    #[tokio::test]
+    async fn evicts_least_recently_used() {
+        let cache = InMemoryCache::new(2);
+        store(&cache, "FILE", "fid=1").await;
+        store(&cache, "FILE", "fid=2").await;
+        assert_eq!(get(&cache, "FILE", "fid=1").await.as_deref(), Some("fid=1"));
+        store(&cache, "FILE", "fid=3").await;
+
+        assert_eq!(get(&cache, "FILE", "fid=2").await, None);
+        assert_eq!(get(&cache, "FILE", "fid=1").await.as_deref(), Some("fid=1"));
+        assert_eq!(get(&cache, "FILE", "fid=3").await.as_deref(), Some("fid=3"));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.stats(), CacheStats { hits: 3, misses: 1, evictions: 1 });
+
+        // replacing an entry is not an eviction
+        store(&cache, "FILE", "fid=3").await;
+        assert_eq!(cache.stats().evictions, 1);
+    }
+
+    #[tokio::test]
+    async fn expires_per_command() {
+        let cache = InMemoryCache::default()
+            .with_default_ttl(None)
+            .with_ttl("MYLIST", Some(Duration::from_millis(20)));
+        store(&cache, "FILE", "fid=1").await;
+        store(&cache, "MYLIST", "fid=1").await;
+        tokio::time::sleep(Duration::from_millis(40)).await;
+
+        assert_eq!(get(&cache, "FILE", "fid=1").await.as_deref(), Some("fid=1"));
+        assert_eq!(get(&cache, "MYLIST", "fid=1").await, None);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.stats(), CacheStats { hits: 1, misses: 1, evictions: 0 });
+
+        let negative = ReplyClass::Negative { ttl: Duration::from_millis(20) };
+        let key = CacheKey::new("FILE", "fid=2", &[]);
+        cache.store(&key, "320", "NO SUCH FILE", "", negative).await.unwrap();
+        assert!(get(&cache, "FILE", "fid=2").await.is_some());
+        tokio::time::sleep(Duration::from_millis(40)).await;
+        assert_eq!(get(&cache, "FILE", "fid=2").await, None);
+    }
+}
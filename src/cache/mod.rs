@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use async_trait::async_trait;
+
+#[cfg(feature = "fs-cache")]
+mod fs;
+mod key;
+mod memory;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+#[cfg(feature = "fs-cache")]
+pub use self::fs::{FsCache, FsCacheError};
+pub use self::key::CacheKey;
+pub use self::memory::{CacheStats, InMemoryCache};
+#[cfg(feature = "sqlite")]
+pub use self::sqlite::{SqliteCache, SqliteCacheError};
+
+/// A stored reply and the masks it was fetched with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CachedReply {
+    /// As returned by `CacheKey::masks`.
+    pub masks: String,
+    pub code: String,
+    pub reply: String,
+    pub data: String,
+}
+
+/// Replies are stored per command and `CacheKey::target`, a store replaces
+/// the reply for the same target whatever masks it was fetched with. Masks are
+/// never merged: a request the cached reply cannot answer, say one asking for
+/// a field it lacks, is fetched with just its own masks and that reply evicts
+/// the stored one, even where the stored one had fields the new one lacks.
+/// Requests for those fields miss until they are fetched again.
+#[async_trait]
+pub trait AniDbCache {
+    type Error: std::error::Error + std::fmt::Debug + std::fmt::Display;
+    async fn get(
+        &self,
+        key: &CacheKey
+    ) -> Result<Option<CachedReply>, Self::Error>;
+    async fn store(
+        &self,
+        key: &CacheKey,
+        code: &str,
+        reply: &str,
+        data: &str,
+        class: ReplyClass
+    ) -> Result<(), Self::Error>;
+}
+
+/// What kind of reply is being stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ReplyClass {
+    /// A 2xx reply or another answer as good, kept for the cache's own TTL
+    /// for the command.
+    Success,
+    /// A definitive negative answer like 320 NO SUCH FILE, allowed by the
+    /// client's `CachePolicy`, to be kept no longer than `ttl`.
+    Negative { ttl: Duration },
+}
+
+// Answers outside 2xx that are as stable as a success, the fids of every file
+// matching a FILE request are as worth keeping as a single file.
+const SUCCESS_CODES: &[(&str, &str)] = &[("FILE", "322")];
+
+/// Decides which replies the client caches. Only successful replies, 2xx and
+/// 322 MULTIPLE FILES FOUND to `FILE`, are cached unless negative caching is
+/// enabled for a command and code, errors like 602 SERVER BUSY should never
+/// be.
+#[derive(Debug, Clone, Default)]
+pub struct CachePolicy {
+    negative: HashMap<(String, String), Duration>,
+}
+
+impl CachePolicy {
+    pub fn new() -> CachePolicy {
+        CachePolicy::default()
+    }
+
+    /// Also caches `code` replies to `command` for up to `ttl`, e.g. `320`
+    /// for `FILE`.
+    pub fn cache_negative(mut self, command: &str, code: &str, ttl: Duration) -> CachePolicy {
+        self.negative.insert((command.to_uppercase(), code.to_string()), ttl);
+        self
+    }
+
+    /// Returns `None` for replies that must not be cached.
+    pub fn classify(&self, command: &str, code: &str) -> Option<ReplyClass> {
+        let command = command.to_uppercase();
+        if (code.len() == 3 && code.starts_with('2')) || SUCCESS_CODES.contains(&(&command, code)) {
+            return Some(ReplyClass::Success);
+        }
+        self.negative.get(&(command, code.to_string()))
+            .map(|&ttl| ReplyClass::Negative { ttl })
+    }
+}
+
+const DEFAULT_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+// Time to live per command, `None` meaning replies never expire.
+#[derive(Debug, Clone)]
+pub(crate) struct Ttls {
+    default: Option<Duration>,
+    commands: HashMap<String, Option<Duration>>,
+}
+
+impl Default for Ttls {
+    fn default() -> Ttls {
+        Ttls {
+            default: Some(DEFAULT_TTL),
+            commands: HashMap::new(),
+        }
+    }
+}
+
+impl Ttls {
+    pub(crate) fn set_default(&mut self, ttl: Option<Duration>) {
+        self.default = ttl;
+    }
+
+    pub(crate) fn set(&mut self, command: &str, ttl: Option<Duration>) {
+        self.commands.insert(command.to_uppercase(), ttl);
+    }
+
+    pub(crate) fn get(&self, command: &str) -> Option<Duration> {
+        match self.commands.get(&command.to_uppercase()) {
+            Some(ttl) => *ttl,
+            None => self.default,
+        }
+    }
+
+    /// Negative replies are kept for the shorter of their own and the
+    /// command's TTL.
+    pub(crate) fn for_reply(&self, command: &str, class: ReplyClass) -> Option<Duration> {
+        match (class, self.get(command)) {
+            (ReplyClass::Success, ttl) => ttl,
+            (ReplyClass::Negative { ttl }, Some(command_ttl)) => Some(ttl.min(command_ttl)),
+            (ReplyClass::Negative { ttl }, None) => Some(ttl),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_replies() {
+        let day = Duration::from_secs(24 * 60 * 60);
+        let policy = CachePolicy::new().cache_negative("FILE", "320", day);
+        assert_eq!(policy.classify("FILE", "220"), Some(ReplyClass::Success));
+        assert_eq!(policy.classify("ANIME", "230"), Some(ReplyClass::Success));
+        assert_eq!(policy.classify("FILE", "320"), Some(ReplyClass::Negative { ttl: day }));
+        assert_eq!(policy.classify("ANIME", "320"), None);
+        assert_eq!(policy.classify("ANIME", "330"), None);
+        assert_eq!(policy.classify("FILE", "322"), Some(ReplyClass::Success));
+        assert_eq!(policy.classify("file", "322"), Some(ReplyClass::Success));
+        assert_eq!(policy.classify("ANIME", "322"), None);
+        for code in ["500", "502", "555", "598", "600", "602", "604"] {
+            assert_eq!(policy.classify("FILE", code), None, "{}", code);
+        }
+    }
+
+    #[test]
+    fn negative_ttl() {
+        let mut ttls = Ttls::default();
+        ttls.set("FILE", Some(Duration::from_secs(60)));
+        ttls.set("MYLIST", None);
+        let negative = |secs| ReplyClass::Negative { ttl: Duration::from_secs(secs) };
+        assert_eq!(ttls.for_reply("FILE", ReplyClass::Success), Some(Duration::from_secs(60)));
+        assert_eq!(ttls.for_reply("FILE", negative(10)), Some(Duration::from_secs(10)));
+        assert_eq!(ttls.for_reply("FILE", negative(600)), Some(Duration::from_secs(60)));
+        assert_eq!(ttls.for_reply("MYLIST", ReplyClass::Success), None);
+        assert_eq!(ttls.for_reply("MYLIST", negative(10)), Some(Duration::from_secs(10)));
+    }
+}
@@ -0,0 +1,277 @@
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension};
+
+use super::{AniDbCache, CacheKey, CachedReply, ReplyClass, Ttls};
+
+// Each entry upgrades the schema by one version, the current version is kept
+// in `PRAGMA user_version`. Never edit a released migration, add a new one.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE cache (
+        command TEXT NOT NULL,
+        -- `CacheKey::target`, the masks are kept apart
+        args TEXT NOT NULL,
+        masks TEXT NOT NULL,
+        code TEXT NOT NULL,
+        reply TEXT NOT NULL,
+        data TEXT NOT NULL,
+        fetched_at INTEGER NOT NULL,
+        -- milliseconds a negative reply may be kept, NULL for successful ones
+        negative_ttl INTEGER,
+        PRIMARY KEY (command, args)
+    );
+    CREATE INDEX cache_fetched_at ON cache (command, fetched_at);",
+];
+
+#[derive(Debug, thiserror::Error)]
+pub enum SqliteCacheError {
+    #[error("SQLite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("Cache schema version {0} is newer than supported version {1}")]
+    UnsupportedSchema(usize, usize),
+}
+
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
+
+fn migrate(conn: &mut Connection) -> Result<(), SqliteCacheError> {
+    let version: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if version > MIGRATIONS.len() {
+        return Err(SqliteCacheError::UnsupportedSchema(version, MIGRATIONS.len()));
+    }
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", i + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+/// Cache persisted in a SQLite database, so replies survive restarts. Replies
+/// expire based on when they were fetched and the TTL configured for their
+/// command at the time they are read, one day unless configured otherwise.
+pub struct SqliteCache {
+    conn: Mutex<Connection>,
+    ttls: Ttls,
+}
+
+impl SqliteCache {
+    /// Opens or creates the database, migrating it to the current schema.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<SqliteCache, SqliteCacheError> {
+        SqliteCache::from_connection(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> Result<SqliteCache, SqliteCacheError> {
+        SqliteCache::from_connection(Connection::open_in_memory()?)
+    }
+
+    fn from_connection(mut conn: Connection) -> Result<SqliteCache, SqliteCacheError> {
+        migrate(&mut conn)?;
+        Ok(SqliteCache {
+            conn: Mutex::new(conn),
+            ttls: Ttls::default(),
+        })
+    }
+
+    /// TTL for commands without their own, `None` never expires.
+    pub fn with_default_ttl(mut self, ttl: Option<Duration>) -> SqliteCache {
+        self.ttls.set_default(ttl);
+        self
+    }
+
+    /// TTL for a single command, e.g. long for `FILE` and short for `MYLIST`.
+    pub fn with_ttl(mut self, command: &str, ttl: Option<Duration>) -> SqliteCache {
+        self.ttls.set(command, ttl);
+        self
+    }
+
+    fn is_expired(&self, command: &str, fetched_at: i64, negative_ttl: Option<i64>, now: i64) -> bool {
+        let class = match negative_ttl {
+            Some(ttl) => ReplyClass::Negative { ttl: Duration::from_millis(ttl.max(0) as u64) },
+            None => ReplyClass::Success,
+        };
+        match self.ttls.for_reply(command, class) {
+            Some(ttl) => now.saturating_sub(fetched_at) >= ttl.as_millis() as i64,
+            None => false,
+        }
+    }
+
+    /// Deletes every expired reply, returning how many were removed.
+    pub fn prune(&self) -> Result<usize, SqliteCacheError> {
+        let conn = self.conn.lock().unwrap();
+        let commands = conn.prepare("SELECT DISTINCT command FROM cache")?
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        let now = now_millis();
+        let mut removed = conn.execute(
+            "DELETE FROM cache WHERE negative_ttl IS NOT NULL AND fetched_at + negative_ttl <= ?1",
+            params![now],
+        )?;
+        for command in commands {
+            if let Some(ttl) = self.ttls.get(&command) {
+                removed += conn.execute(
+                    "DELETE FROM cache WHERE command = ?1 AND fetched_at <= ?2",
+                    params![command, now - ttl.as_millis() as i64],
+                )?;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Prunes expired replies and reclaims the space they used on disk.
+    pub fn vacuum(&self) -> Result<usize, SqliteCacheError> {
+        let removed = self.prune()?;
+        self.conn.lock().unwrap().execute_batch("VACUUM")?;
+        Ok(removed)
+    }
+}
+
+// SQLite calls are quick local operations, so they run inline rather than
+// on the blocking pool.
+#[async_trait]
+impl AniDbCache for SqliteCache {
+    type Error = SqliteCacheError;
+
+    async fn get(
+        &self,
+        key: &CacheKey
+    ) -> Result<Option<CachedReply>, Self::Error> {
+        let (command, args) = (key.command(), key.target());
+        let conn = self.conn.lock().unwrap();
+        let row = conn.query_row(
+            "SELECT masks, code, reply, data, fetched_at, negative_ttl FROM cache
+             WHERE command = ?1 AND args = ?2",
+            params![command, args],
+            |row| Ok((
+                CachedReply {
+                    masks: row.get(0)?,
+                    code: row.get(1)?,
+                    reply: row.get(2)?,
+                    data: row.get(3)?,
+                },
+                row.get(4)?,
+                row.get(5)?,
+            )),
+        ).optional()?;
+        match row {
+            Some((_, fetched_at, negative_ttl))
+                if self.is_expired(command, fetched_at, negative_ttl, now_millis()) => {
+                conn.execute(
+                    "DELETE FROM cache WHERE command = ?1 AND args = ?2",
+                    params![command, args],
+                )?;
+                Ok(None)
+            }
+            Some((reply, _, _)) => Ok(Some(reply)),
+            None => Ok(None),
+        }
+    }
+
+    async fn store(
+        &self,
+        key: &CacheKey,
+        code: &str,
+        reply: &str,
+        data: &str,
+        class: ReplyClass
+    ) -> Result<(), Self::Error> {
+        let negative_ttl = match class {
+            ReplyClass::Success => None,
+            ReplyClass::Negative { ttl } => Some(ttl.as_millis() as i64),
+        };
+        self.conn.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO cache
+             (command, args, masks, code, reply, data, fetched_at, negative_ttl)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                key.command(), key.target(), key.masks(), code, reply, data, now_millis(),
+                negative_ttl
+            ],
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn key(command: &str, args: &str) -> CacheKey {
+        CacheKey::new(command, args, &["fmask"])
+    }
+
+    #[tokio::test]
+    async fn persists_across_connections() {
+        let path = std::env::temp_dir()
+            .join(format!("anidb-sqlite-cache-{}.db", std::process::id()));
+        {
+            let cache = SqliteCache::open(&path).unwrap();
+            let key = key("FILE", "fmask=7800000000&fid=1");
+            cache.store(&key, "220", "FILE", "1|2", ReplyClass::Success).await.unwrap();
+        }
+        let cache = SqliteCache::open(&path).unwrap();
+        assert_eq!(
+            cache.get(&key("FILE", "fid=1&fmask=7000000000")).await.unwrap(),
+            Some(CachedReply {
+                masks: String::from("fmask=7800000000"),
+                code: String::from("220"),
+                reply: String::from("FILE"),
+                data: String::from("1|2"),
+            })
+        );
+        assert_eq!(cache.get(&key("FILE", "fid=2")).await.unwrap(), None);
+        drop(cache);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn expires_and_prunes() {
+        let cache = SqliteCache::open_in_memory().unwrap()
+            .with_default_ttl(None)
+            .with_ttl("MYLIST", Some(Duration::from_millis(20)));
+        for args in ["lid=1", "lid=2"] {
+            cache.store(&key("MYLIST", args), "221", "MYLIST", "", ReplyClass::Success).await.unwrap();
+        }
+        cache.store(&key("FILE", "fid=1"), "220", "FILE", "", ReplyClass::Success).await.unwrap();
+        let negative = ReplyClass::Negative { ttl: Duration::from_millis(20) };
+        for args in ["fid=2", "fid=3"] {
+            cache.store(&key("FILE", args), "320", "NO SUCH FILE", "", negative).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(40)).await;
+
+        assert_eq!(cache.get(&key("MYLIST", "lid=1")).await.unwrap(), None);
+        assert_eq!(cache.get(&key("FILE", "fid=2")).await.unwrap(), None);
+        assert!(cache.get(&key("FILE", "fid=1")).await.unwrap().is_some());
+        // lid=1 and fid=2 were already dropped when they were read
+        assert_eq!(cache.vacuum().unwrap(), 2);
+        assert_eq!(cache.prune().unwrap(), 0);
+        assert!(cache.get(&key("FILE", "fid=1")).await.unwrap().is_some());
+    }
+
+    #[test]
+    fn migrations() {
+        let cache = SqliteCache::open_in_memory().unwrap();
+        let mut conn = cache.conn.into_inner().unwrap();
+        let version: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+
+        // migrating again is a no-op
+        migrate(&mut conn).unwrap();
+
+        conn.pragma_update(None, "user_version", MIGRATIONS.len() + 1).unwrap();
+        assert!(matches!(
+            SqliteCache::from_connection(conn),
+            Err(SqliteCacheError::UnsupportedSchema(_, _))
+        ));
+    }
+}
@@ -1,7 +1,6 @@
 use std::collections::HashMap;
 use std::time::Duration;
 use std::sync::{Arc, Mutex};
-use std::net::ToSocketAddrs;
 use tokio::net::UdpSocket;
 use tokio::sync::{
     oneshot,
@@ -10,7 +9,7 @@ use tokio::sync::{
 };
 use tokio::time::sleep;
 
-use crate::cache::AniDbCache;
+use crate::cache::{AniDbCache, CachePolicy};
 use crate::requests::{
     AniDbRequest,
     auth::AuthRequest
@@ -32,16 +31,18 @@ macro_rules! expect_next {
                 $offset = 0;
                 continue;
             }
-        };
+        }
     }
 }
 
+type RequestMap = Arc<Mutex<HashMap<String, oneshot::Sender<(String, String, String)>>>>;
+
 #[derive(Clone)]
 pub struct AniDbClient<C: AniDbCache> {
-    socket: Arc<UdpSocket>,
-    request_map: Arc<Mutex<HashMap<String, oneshot::Sender<(String, String, String)>>>>,
+    request_map: RequestMap,
     request_queue: mpsc::Sender<String>,
     cache: Arc<TokioMutex<C>>,
+    cache_policy: Arc<CachePolicy>,
     client_name: String,
     client_version: i32,
     username: String,
@@ -56,7 +57,7 @@ impl<C> AniDbClient<C> where C: AniDbCache {
         user: &str,
         pass: &str
     ) -> Result<AniDbClient<C>, AniDbError> {
-        Self::new_with_client(cache, user, pass, &CLIENT_NAME, CLIENT_VERSION)
+        Self::new_with_client(cache, user, pass, CLIENT_NAME, CLIENT_VERSION)
             .await
     }
 
@@ -96,8 +97,7 @@ impl<C> AniDbClient<C> where C: AniDbCache {
                 }
             });
         }
-        let request_map: Arc<Mutex<HashMap<String, oneshot::Sender<(String, String, String)>>>>
-            = Arc::new(Mutex::new(HashMap::new()));
+        let request_map: RequestMap = Arc::new(Mutex::new(HashMap::new()));
         {
             let socket = socket.clone();
             let request_map = request_map.clone();
@@ -112,10 +112,7 @@ impl<C> AniDbClient<C> where C: AniDbCache {
                                 // we expect more bytes?
                                 eprintln!(
                                     "expecting more bytes after: {}",
-                                    match std::str::from_utf8(&buf[..buf_len]) {
-                                        Ok(s) => s,
-                                        Err(_e) => "failed to decode as utf8"
-                                    }
+                                    std::str::from_utf8(&buf[..buf_len]).unwrap_or("failed to decode as utf8")
                                 );
                                 offset = buf_len;
                                 continue;
@@ -161,8 +158,8 @@ impl<C> AniDbClient<C> where C: AniDbCache {
         }
 
         Ok(AniDbClient {
-            socket,
             cache,
+            cache_policy: Arc::new(CachePolicy::default()),
             username: user.to_owned(),
             password: pass.to_owned(),
             request_map,
@@ -174,6 +171,12 @@ impl<C> AniDbClient<C> where C: AniDbCache {
         })
     }
 
+    /// Decides which replies get cached, only successful ones by default.
+    pub fn with_cache_policy(mut self, policy: CachePolicy) -> Self {
+        self.cache_policy = Arc::new(policy);
+        self
+    }
+
     async fn connect(
         &self,
     ) -> Result<String, AniDbError> {
@@ -231,11 +234,26 @@ impl<C> AniDbClient<C> where C: AniDbCache {
     where R: AniDbRequest
     {
         let args: String = request.encode()?;
-        if let Some((code, resp_str, data)) = {
+        let key = request.cache_key()?;
+        let cached = {
             let cache = self.cache.lock().await;
-            cache.get(R::name(), &args).await
+            cache.get(&key).await
                 .map_err(|e| AniDbError::CacheError(format!("{}", e)))?
-        } {
+        };
+        // skip replies the policy would not have cached, e.g. errors stored
+        // by older versions
+        let cached = cached.filter(|cached| {
+            self.cache_policy.classify(R::name(), &cached.code).is_some()
+        });
+        let cached = cached.and_then(|cached| {
+            let data = if cached.masks == key.masks() {
+                cached.data
+            } else {
+                request.reuse_cached(&key.with_masks(&cached.masks), &cached.code, &cached.data)?
+            };
+            Some((cached.code, cached.reply, data))
+        });
+        if let Some((code, resp_str, data)) = cached {
             dbg!("FROM CACHE!");
             request.decode_response(&code, &resp_str, &data)
         } else {
@@ -260,10 +278,10 @@ impl<C> AniDbClient<C> where C: AniDbCache {
             }
             self.request_queue.send(req_str).await?;
             let (code, reply, data) = receiver.await?;
-            {
+            if let Some(class) = self.cache_policy.classify(R::name(), &code) {
                 let cache = self.cache.lock().await;
                 cache.store(
-                    R::name(), &args, &code, &reply, &data
+                    &key, &code, &reply, &data, class
                 ).await
                     .map_err(|e| AniDbError::CacheError(format!("{}", e)))?
             }
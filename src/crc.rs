@@ -3,22 +3,122 @@ use std::io::{self, Read};
 use std::path::Path;
 use crcx::{Crc, CRC_32_ISO_HDLC};
 
-const BUFFER_SIZE: usize = 1_048_576;
+use crate::progress::HashControl;
 
+static CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+pub fn crc_hash_reader<R: Read>(reader: R, mut control: HashControl<'_>) -> io::Result<String> {
+    let mut hasher = CRC32.digest();
+    control.read_all(reader, |data| hasher.update(data))?;
+    Ok(format!("{:08x}", hasher.finalize()))
+}
+
+/// Returns the CRC32 as 8 lowercase hex digits.
 pub fn crc_hash<P: AsRef<Path>>(p: P) -> io::Result<String> {
-    let mut file = OpenOptions::new()
+    crc_hash_with_control(p, HashControl::default())
+}
+
+pub fn crc_hash_with_control<P: AsRef<Path>>(
+    p: P,
+    mut control: HashControl<'_>
+) -> io::Result<String> {
+    let file = OpenOptions::new()
         .read(true)
         .open(p)?;
-    let mut buf = [0u8; BUFFER_SIZE];
-
-    let crc = Crc::<u32>::new(&CRC_32_ISO_HDLC);
-    let mut hasher = crc.digest();
-    loop {
-        let bytes_read = file.read(&mut buf)?;
-        if bytes_read == 0 {
-            break;
+    control.default_total(file.metadata()?.len());
+    let mut hasher = CRC32.digest();
+    control.read_file(file, |data| hasher.update(data))?;
+    Ok(format!("{:08x}", hasher.finalize()))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CrcCheck {
+    Match,
+    Mismatch {
+        expected: String,
+        actual: String,
+    },
+    /// The file name has no CRC to check against.
+    Absent,
+}
+
+/// Extracts the CRC32 embedded in a file name like `[Group] Show - 01
+/// [ABCD1234].mkv`, taking the last bracketed group of 8 hex digits.
+pub fn filename_crc(name: &str) -> Option<&str> {
+    name.rmatch_indices(['[', '('])
+        .find_map(|(i, open)| {
+            let close = if open == "[" { ']' } else { ')' };
+            let crc = name.get(i + 1..i + 9)?;
+            let valid = crc.bytes().all(|b| b.is_ascii_hexdigit())
+                && name[i + 9..].starts_with(close);
+            valid.then_some(crc)
+        })
+}
+
+/// Compares the file's CRC32 with the one in its file name, ignoring case.
+/// The file is only read if its name has a CRC.
+pub fn verify_crc<P: AsRef<Path>>(p: P) -> io::Result<CrcCheck> {
+    verify_crc_with_control(p, HashControl::default())
+}
+
+pub fn verify_crc_with_control<P: AsRef<Path>>(
+    p: P,
+    control: HashControl<'_>
+) -> io::Result<CrcCheck> {
+    let p = p.as_ref();
+    let expected = match p.file_name().and_then(|name| name.to_str()).and_then(filename_crc) {
+        Some(expected) => expected,
+        None => return Ok(CrcCheck::Absent),
+    };
+    let actual = crc_hash_with_control(p, control)?;
+    Ok(if actual.eq_ignore_ascii_case(expected) {
+        CrcCheck::Match
+    } else {
+        CrcCheck::Mismatch {
+            expected: expected.to_string(),
+            actual,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn zero_padded() {
+        assert_eq!(crc_hash_reader(&b"abc"[..], HashControl::new()).unwrap(), "352441c2");
+        assert_eq!(crc_hash_reader(&b"62"[..], HashControl::new()).unwrap(), "0012d20a");
+    }
+
+    #[test]
+    fn extracts_filename_crc() {
+        assert_eq!(filename_crc("[Group] Show - 01 [1080p][ABCD1234].mkv"), Some("ABCD1234"));
+        assert_eq!(filename_crc("[DEADBEEF] Show - 01 (0012d20a).mkv"), Some("0012d20a"));
+        assert_eq!(filename_crc("[Group] Show - 01 [1080p].mkv"), None);
+        assert_eq!(filename_crc("Show [ABCD12345].mkv"), None);
+        assert_eq!(filename_crc("Show [ABCD1234).mkv"), None);
+        assert_eq!(filename_crc("Show [ABCD"), None);
+    }
+
+    #[test]
+    fn verifies_file() {
+        let dir = std::env::temp_dir();
+        let id = std::process::id();
+        let cases = [
+            (format!("anidb-crc-{} [0012D20A].mkv", id), CrcCheck::Match),
+            (format!("anidb-crc-{} [352441c2].mkv", id), CrcCheck::Mismatch {
+                expected: String::from("352441c2"),
+                actual: String::from("0012d20a"),
+            }),
+            (format!("anidb-crc-{}.mkv", id), CrcCheck::Absent),
+        ];
+        for (name, expected) in cases.iter() {
+            let path = dir.join(name);
+            fs::write(&path, b"62").unwrap();
+            assert_eq!(&verify_crc(&path).unwrap(), expected, "{}", name);
+            fs::remove_file(path).unwrap();
         }
-        hasher.update(&buf[..bytes_read]);
     }
-    Ok(format!("{:x}", hasher.finalize()))
 }
@@ -1,41 +1,199 @@
 use std::fs::OpenOptions;
-use std::io::{self, Read};
+use std::io::{self, Read, Write};
 use std::path::Path;
 use md4::{Md4, Digest};
 
-const CHUNK_SIZE: usize = 9_728_000;
-const BUFFER_SIZE: usize = 1_048_576;
+use crate::progress::HashControl;
+
+pub(crate) const CHUNK_SIZE: usize = 9_728_000;
+
+/// How to hash data whose size is an exact multiple of the chunk size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Ed2kVariant {
+    /// Appends the hash of an empty chunk after the last full chunk, as the
+    /// original eDonkey client did. This is the hash AniDB uses.
+    #[default]
+    Red,
+    /// Leaves out the empty chunk, as newer eMule versions do.
+    Blue,
+}
+
+/// Incremental ed2k hasher, data can be fed in pieces of any size either
+/// through `update` or as a `std::io::Write`.
+pub struct Ed2kHasher {
+    variant: Ed2kVariant,
+    hasher: Md4,
+    hashlist: Vec<u8>,
+    chunk_bytes: usize,
+    total_bytes: usize,
+}
+
+impl Ed2kHasher {
+    pub fn new() -> Ed2kHasher {
+        Ed2kHasher::with_variant(Ed2kVariant::default())
+    }
+
+    pub fn with_variant(variant: Ed2kVariant) -> Ed2kHasher {
+        Ed2kHasher {
+            variant,
+            hasher: Md4::new(),
+            hashlist: Vec::new(),
+            chunk_bytes: 0,
+            total_bytes: 0,
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            // only close a chunk once more data arrives, whether a full
+            // last chunk is followed by an empty one depends on the variant
+            if self.chunk_bytes == CHUNK_SIZE {
+                self.hashlist.extend(self.hasher.finalize_reset());
+                self.chunk_bytes = 0;
+            }
+            let take = (CHUNK_SIZE - self.chunk_bytes).min(data.len());
+            self.hasher.update(&data[..take]);
+            self.chunk_bytes += take;
+            self.total_bytes += take;
+            data = &data[take..];
+        }
+    }
+
+    /// Returns the hex encoded hash and the number of bytes hashed.
+    pub fn finalize(mut self) -> (String, usize) {
+        self.hashlist.extend(self.hasher.finalize());
+        (ed2k_from_chunk_hashes(self.hashlist, self.total_bytes, self.variant), self.total_bytes)
+    }
+}
+
+/// Builds the ed2k hash of `size` bytes from the concatenated MD4 hashes of
+/// its chunks, in order.
+pub(crate) fn ed2k_from_chunk_hashes(
+    mut hashlist: Vec<u8>,
+    size: usize,
+    variant: Ed2kVariant
+) -> String {
+    if hashlist.is_empty() {
+        hashlist.extend(Md4::digest(&[]));
+    }
+    if variant == Ed2kVariant::Red && size > 0 && size.is_multiple_of(CHUNK_SIZE) {
+        hashlist.extend(Md4::digest(&[]));
+    }
+    if hashlist.len() == 16 {
+        hex::encode(hashlist)
+    } else {
+        hex::encode(Md4::digest(&hashlist))
+    }
+}
+
+impl Default for Ed2kHasher {
+    fn default() -> Ed2kHasher {
+        Ed2kHasher::new()
+    }
+}
+
+impl Write for Ed2kHasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub fn ed2k_hash_reader<R: Read>(
+    reader: R,
+    variant: Ed2kVariant,
+    mut control: HashControl<'_>
+) -> io::Result<(String, usize)> {
+    let mut hasher = Ed2kHasher::with_variant(variant);
+    control.read_all(reader, |data| hasher.update(data))?;
+    Ok(hasher.finalize())
+}
 
 pub fn ed2k_hash<P: AsRef<Path>>(p: P) -> io::Result<(String, usize)> {
-    let mut file = OpenOptions::new()
+    ed2k_hash_with_variant(p, Ed2kVariant::default())
+}
+
+pub fn ed2k_hash_with_variant<P: AsRef<Path>>(
+    p: P,
+    variant: Ed2kVariant
+) -> io::Result<(String, usize)> {
+    ed2k_hash_with_control(p, variant, HashControl::default())
+}
+
+pub fn ed2k_hash_with_control<P: AsRef<Path>>(
+    p: P,
+    variant: Ed2kVariant,
+    mut control: HashControl<'_>
+) -> io::Result<(String, usize)> {
+    let file = OpenOptions::new()
         .read(true)
         .open(p)?;
+    control.default_total(file.metadata()?.len());
+    let mut hasher = Ed2kHasher::with_variant(variant);
+    control.read_file(file, |data| hasher.update(data))?;
+    Ok(hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    const EMPTY_MD4: &str = "31d6cfe0d16ae931b73c59d7e0c089c0";
+
+    fn synthetic_file(size: usize) -> PathBuf {
+        let path = std::env::temp_dir()
+            .join(format!("anidb-ed2k-{}-{}", std::process::id(), size));
+        let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        fs::write(&path, data).unwrap();
+        path
+    }
 
-    let mut buf = [0u8; BUFFER_SIZE];
-    let mut hashlist: Vec<u8> = vec![];
-    let mut hasher = Md4::new();
-    let mut chunk_bytes_read = 0;
-    let mut total_bytes_read = 0;
-    loop {
-        let bytes_read = file.read(&mut buf)?;
-        if bytes_read == 0 {
-            break;
+    // straight from the definition: md4 of each chunk, then md4 of the
+    // concatenated chunk hashes unless there is only one
+    fn expected(size: usize, variant: Ed2kVariant) -> String {
+        let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        let mut hashes: Vec<Vec<u8>> = data.chunks(CHUNK_SIZE)
+            .map(|chunk| Md4::digest(chunk).to_vec())
+            .collect();
+        let red_tail = variant == Ed2kVariant::Red && size.is_multiple_of(CHUNK_SIZE);
+        if hashes.is_empty() || red_tail {
+            hashes.push(Md4::digest(&[]).to_vec());
         }
-        if chunk_bytes_read + bytes_read > CHUNK_SIZE {
-            let take = CHUNK_SIZE - chunk_bytes_read;
-            hasher.update(&buf[..take]);
-            hashlist.extend(hasher.finalize_reset());
-            hasher.update(&buf[take..bytes_read]);
-            chunk_bytes_read = bytes_read - take;
+        if hashes.len() == 1 {
+            hex::encode(&hashes[0])
         } else {
-            hasher.update(&buf[..bytes_read]);
-            chunk_bytes_read += bytes_read;
+            hex::encode(Md4::digest(&hashes.concat()))
         }
-        total_bytes_read += bytes_read;
     }
-    if hashlist.len() > 0 {
-        hashlist.extend(hasher.finalize_reset());
-        hasher.update(&hashlist);
+
+    #[test]
+    fn chunk_boundaries() {
+        let sizes = [0, 1, CHUNK_SIZE - 1, CHUNK_SIZE, CHUNK_SIZE + 1, 2 * CHUNK_SIZE];
+        for &size in &sizes {
+            let path = synthetic_file(size);
+            for &variant in &[Ed2kVariant::Red, Ed2kVariant::Blue] {
+                let (hash, len) = ed2k_hash_with_variant(&path, variant).unwrap();
+                assert_eq!(len, size);
+                assert_eq!(hash, expected(size, variant), "{} bytes, {:?}", size, variant);
+            }
+            let red = ed2k_hash(&path).unwrap().0;
+            let blue = ed2k_hash_with_variant(&path, Ed2kVariant::Blue).unwrap().0;
+            assert_eq!(red == blue, size == 0 || !size.is_multiple_of(CHUNK_SIZE), "{} bytes", size);
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn empty_input() {
+        for &variant in &[Ed2kVariant::Red, Ed2kVariant::Blue] {
+            let (hash, _) = ed2k_hash_reader(&b""[..], variant, HashControl::new()).unwrap();
+            assert_eq!(hash, EMPTY_MD4);
+        }
     }
-    Ok((hex::encode(hasher.finalize()), total_bytes_read))
 }
@@ -43,7 +43,7 @@ pub enum AniDbError {
     #[error("IoError: {0}")]
     IoError(#[from] std::io::Error),
     #[error("Serialization Error: {0}")]
-    SerializationError(#[from] serde_urlencoded::ser::Error),
+    SerializationError(#[from] crate::ser::Error),
     #[error("Deserialization Error: {0}")]
     DecodeError(String),
     #[error("Unexpected Error: {0}")]
@@ -89,7 +89,7 @@ macro_rules! impl_into_anidberror {
         impl_into_anidberror_internal!($name, std::str::Utf8Error);
         impl_into_anidberror_internal!($name, std::io::Error);
         impl_into_anidberror_internal!($name, std::num::ParseIntError);
-        impl_into_anidberror_internal!($name, serde_urlencoded::ser::Error);
+        impl_into_anidberror_internal!($name, crate::ser::Error);
         impl_into_anidberror_internal!($name, tokio::sync::oneshot::error::RecvError);
         impl_into_anidberror_internal!($name, tokio::sync::mpsc::error::SendError<String>);
     }
@@ -111,7 +111,7 @@ impl From<(&str, &str)> for AniDbError {
             "601" => AniDbError::OutOfService,
             "602" => AniDbError::ServerBusy,
             "604" => AniDbError::Timeout,
-            code @ _ => AniDbError::UnknownError(format!("{0} {1}", code, buf)),
+            code => AniDbError::UnknownError(format!("{0} {1}", code, buf)),
         }
     }
 }
@@ -0,0 +1,327 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::ed2k::Ed2kVariant;
+use super::FileHashes;
+
+/// Identifies a file's contents by where it is and what the filesystem says
+/// about it, cached hashes are only valid while all of these stay the same.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FileKey {
+    /// Canonical path of the file.
+    pub path: PathBuf,
+    pub size: u64,
+    pub mtime: SystemTime,
+    /// Always 0 on platforms without inodes.
+    pub inode: u64,
+}
+
+#[cfg(unix)]
+fn inode(metadata: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.ino()
+}
+
+#[cfg(not(unix))]
+fn inode(_metadata: &fs::Metadata) -> u64 {
+    0
+}
+
+impl FileKey {
+    pub fn from_path<P: AsRef<Path>>(p: P) -> io::Result<FileKey> {
+        let path = fs::canonicalize(p)?;
+        let metadata = fs::metadata(&path)?;
+        Ok(FileKey {
+            size: metadata.len(),
+            mtime: metadata.modified()?,
+            inode: inode(&metadata),
+            path,
+        })
+    }
+}
+
+/// Storage for computed hashes. `get` must only return hashes stored under an
+/// identical key, a key that differs in any part means the file changed.
+pub trait HashCache: Send + Sync {
+    fn get(&self, key: &FileKey) -> io::Result<Option<FileHashes>>;
+    fn store(&self, key: &FileKey, hashes: &FileHashes) -> io::Result<()>;
+}
+
+type Entries = HashMap<PathBuf, (FileKey, FileHashes)>;
+
+/// Hash cache kept in a single file. Stores are appended, so the file only
+/// grows until `compact` is called. Paths that are not valid UTF-8 are not
+/// cached.
+pub struct FileHashCache {
+    path: PathBuf,
+    entries: Mutex<Entries>,
+    log: Mutex<File>,
+}
+
+impl FileHashCache {
+    /// Opens the cache file, creating it if it does not exist. Lines that
+    /// fail to parse are skipped.
+    pub fn open<P: AsRef<Path>>(p: P) -> io::Result<FileHashCache> {
+        let path = p.as_ref().to_path_buf();
+        let log = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        let mut entries = Entries::new();
+        for line in BufReader::new(&log).lines() {
+            if let Some((key, hashes)) = decode_line(&line?) {
+                entries.insert(key.path.clone(), (key, hashes));
+            }
+        }
+        Ok(FileHashCache {
+            path,
+            entries: Mutex::new(entries),
+            log: Mutex::new(log),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Rewrites the cache file with one line per file, dropping entries for
+    /// files that changed or no longer exist.
+    pub fn compact(&self) -> io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        let mut log = self.log.lock().unwrap();
+        entries.retain(|_, (key, _)| {
+            FileKey::from_path(&key.path).is_ok_and(|current| current == *key)
+        });
+
+        let tmp_path = self.path.with_extension("tmp");
+        let mut tmp = BufWriter::new(File::create(&tmp_path)?);
+        for (key, hashes) in entries.values() {
+            if let Some(line) = encode_line(key, hashes) {
+                tmp.write_all(line.as_bytes())?;
+            }
+        }
+        tmp.into_inner()?.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+        *log = OpenOptions::new().append(true).open(&self.path)?;
+        Ok(())
+    }
+}
+
+impl HashCache for FileHashCache {
+    fn get(&self, key: &FileKey) -> io::Result<Option<FileHashes>> {
+        let entries = self.entries.lock().unwrap();
+        Ok(match entries.get(&key.path) {
+            Some((stored, hashes)) if stored == key => Some(hashes.clone()),
+            _ => None,
+        })
+    }
+
+    fn store(&self, key: &FileKey, hashes: &FileHashes) -> io::Result<()> {
+        let line = match encode_line(key, hashes) {
+            Some(line) => line,
+            None => return Ok(()),
+        };
+        let mut entries = self.entries.lock().unwrap();
+        self.log.lock().unwrap().write_all(line.as_bytes())?;
+        entries.insert(key.path.clone(), (key.clone(), hashes.clone()));
+        Ok(())
+    }
+}
+
+// One tab separated line per entry:
+// size, mtime as secs.nanos, inode, ed2k, md5, sha1, crc32 ("-" when not
+// computed) and finally the path with `\`, tab and line breaks escaped. A blue
+// ed2k digest is prefixed with `blue:`, lines written before the variant was
+// recorded only ever held red ones.
+
+fn escape_path(path: &str) -> String {
+    path.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+fn unescape_path(input: &str) -> Option<String> {
+    let mut out = String::with_capacity(input.len());
+    let mut chars = input.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        out.push(match chars.next()? {
+            '\\' => '\\',
+            't' => '\t',
+            'n' => '\n',
+            'r' => '\r',
+            _ => return None,
+        });
+    }
+    Some(out)
+}
+
+fn encode_digest(digest: &Option<String>) -> &str {
+    digest.as_deref().unwrap_or("-")
+}
+
+fn decode_digest(input: &str) -> Option<String> {
+    match input {
+        "-" => None,
+        digest => Some(digest.to_string()),
+    }
+}
+
+fn encode_ed2k(hashes: &FileHashes) -> String {
+    match (&hashes.ed2k, hashes.ed2k_variant) {
+        (Some(digest), Ed2kVariant::Blue) => format!("blue:{}", digest),
+        (digest, _) => encode_digest(digest).to_string(),
+    }
+}
+
+fn decode_ed2k(input: &str) -> (Option<String>, Ed2kVariant) {
+    match input.strip_prefix("blue:") {
+        Some(digest) => (Some(digest.to_string()), Ed2kVariant::Blue),
+        None => (decode_digest(input), Ed2kVariant::Red),
+    }
+}
+
+fn encode_line(key: &FileKey, hashes: &FileHashes) -> Option<String> {
+    let mtime = key.mtime.duration_since(UNIX_EPOCH).unwrap_or_default();
+    Some(format!(
+        "{}\t{}.{:09}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+        key.size,
+        mtime.as_secs(),
+        mtime.subsec_nanos(),
+        key.inode,
+        encode_ed2k(hashes),
+        encode_digest(&hashes.md5),
+        encode_digest(&hashes.sha1),
+        encode_digest(&hashes.crc32),
+        escape_path(key.path.to_str()?),
+    ))
+}
+
+fn decode_line(line: &str) -> Option<(FileKey, FileHashes)> {
+    let parts: Vec<&str> = line.splitn(8, '\t').collect();
+    if parts.len() != 8 {
+        return None;
+    }
+    let size: u64 = parts[0].parse().ok()?;
+    let (secs, nanos) = parts[1].split_once('.')?;
+    let mtime = UNIX_EPOCH + Duration::new(secs.parse().ok()?, nanos.parse().ok()?);
+    let key = FileKey {
+        path: PathBuf::from(unescape_path(parts[7])?),
+        size,
+        mtime,
+        inode: parts[2].parse().ok()?,
+    };
+    let (ed2k, ed2k_variant) = decode_ed2k(parts[3]);
+    let hashes = FileHashes {
+        size: size as usize,
+        ed2k,
+        ed2k_variant,
+        md5: decode_digest(parts[4]),
+        sha1: decode_digest(parts[5]),
+        crc32: decode_digest(parts[6]),
+    };
+    Some((key, hashes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::{hash_file_cached, DigestSelection};
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("anidb-hash-cache-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn roundtrip_and_invalidation() {
+        let cache_path = temp_path("roundtrip.cache");
+        let file_path = temp_path("roundtrip\tfile");
+        fs::write(&file_path, b"abc").unwrap();
+
+        let selection = DigestSelection::builder().ed2k(true).crc32(true).build();
+        let cache = FileHashCache::open(&cache_path).unwrap();
+        let hashes = hash_file_cached(&file_path, selection, Ed2kVariant::Red, &cache).unwrap();
+        assert_eq!(cache.len(), 1);
+
+        // reopening replays the log
+        let cache = FileHashCache::open(&cache_path).unwrap();
+        let key = FileKey::from_path(&file_path).unwrap();
+        assert_eq!(cache.get(&key).unwrap(), Some(hashes.clone()));
+
+        // cached hashes are used as is
+        let fake = FileHashes {
+            ed2k: Some(String::from("cached")),
+            ..hashes.clone()
+        };
+        cache.store(&key, &fake).unwrap();
+        let cached = hash_file_cached(&file_path, selection, Ed2kVariant::Red, &cache).unwrap();
+        assert_eq!(cached, fake);
+
+        // any change to the key is a miss
+        for changed in [
+            FileKey { size: key.size + 1, ..key.clone() },
+            FileKey { mtime: key.mtime + Duration::from_secs(1), ..key.clone() },
+            FileKey { inode: key.inode + 1, ..key.clone() },
+            FileKey { path: temp_path("elsewhere"), ..key.clone() },
+        ] {
+            assert_eq!(cache.get(&changed).unwrap(), None);
+        }
+
+        fs::write(&file_path, b"abcd").unwrap();
+        let rehashed = hash_file_cached(&file_path, selection, Ed2kVariant::Red, &cache).unwrap();
+        assert_eq!(rehashed.size, 4);
+        assert_ne!(rehashed.ed2k, fake.ed2k);
+
+        fs::remove_file(&file_path).unwrap();
+        cache.compact().unwrap();
+        assert!(cache.is_empty());
+        assert!(FileHashCache::open(&cache_path).unwrap().is_empty());
+        fs::remove_file(&cache_path).unwrap();
+    }
+
+    #[test]
+    fn records_ed2k_variant() {
+        let cache_path = temp_path("variant.cache");
+        let file_path = temp_path("variant");
+        fs::write(&file_path, b"abc").unwrap();
+
+        let selection = DigestSelection::builder().ed2k(true).crc32(true).build();
+        let cache = FileHashCache::open(&cache_path).unwrap();
+        let key = FileKey::from_path(&file_path).unwrap();
+        let blue = FileHashes {
+            ed2k: Some(String::from("blue")),
+            ed2k_variant: Ed2kVariant::Blue,
+            ..hash_file_cached(&file_path, selection, Ed2kVariant::Red, &cache).unwrap()
+        };
+        cache.store(&key, &blue).unwrap();
+        let cache = FileHashCache::open(&cache_path).unwrap();
+        assert_eq!(cache.get(&key).unwrap(), Some(blue.clone()));
+
+        // a digest of the other variant is recomputed, the rest is kept
+        let red = hash_file_cached(&file_path, selection, Ed2kVariant::Red, &cache).unwrap();
+        assert_eq!(red.ed2k.as_deref(), Some("a448017aaf21d8525fc10ae87aa6729d"));
+        assert_eq!(red.ed2k_variant, Ed2kVariant::Red);
+        assert_eq!(red.crc32, blue.crc32);
+        assert_eq!(cache.get(&key).unwrap(), Some(red));
+
+        // lines from before the variant was recorded hold red digests
+        let (_, hashes) = decode_line("3\t0.000000000\t1\tabcd\t-\t-\t-\t/old").unwrap();
+        assert_eq!((hashes.ed2k.as_deref(), hashes.ed2k_variant), (Some("abcd"), Ed2kVariant::Red));
+
+        fs::remove_file(&file_path).unwrap();
+        fs::remove_file(&cache_path).unwrap();
+    }
+}
@@ -0,0 +1,69 @@
+//! Memory mapped reads for the `mmap` feature.
+//!
+//! Mapping is only sound while nobody truncates the file: reading a mapped
+//! page past the new end of the file raises SIGBUS, which kills the process.
+//! Nothing here can rule that out, another process may shrink the file at any
+//! time, so files are only mapped once the caller has promised otherwise
+//! through the unsafe `HashControl::allow_mmap` or `HashOptions::allow_mmap`.
+
+use std::fs::File;
+use memmap2::Mmap;
+
+/// Maps regular files, `None` for anything that has to be read instead, like
+/// pipes, devices or empty files.
+///
+/// # Safety
+///
+/// `file` must not be truncated while the map is alive.
+pub(crate) unsafe fn map_file(file: &File) -> Option<Mmap> {
+    let metadata = file.metadata().ok()?;
+    if !metadata.is_file() || metadata.len() == 0 {
+        return None;
+    }
+    // SAFETY: the map is only read, and the caller guarantees the file keeps
+    // at least its current length for as long as the map lives
+    unsafe { Mmap::map(file) }.ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::crc::crc_hash_with_control;
+    use crate::ed2k::Ed2kVariant;
+    use crate::hash::{hash_file_with_control, DigestSelection};
+    use crate::progress::HashControl;
+
+    // SAFETY: the tests only map files they created themselves, or none at all
+    fn mapped<'a>() -> HashControl<'a> {
+        unsafe { HashControl::new().allow_mmap() }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn special_files_are_read() {
+        let null = File::open("/dev/null").unwrap();
+        assert!(unsafe { map_file(&null) }.is_none());
+        assert_eq!(crc_hash_with_control("/dev/null", mapped()).unwrap(), "00000000");
+        let selection = DigestSelection::all();
+        let hashes = hash_file_with_control("/dev/null", selection, Ed2kVariant::Red, mapped())
+            .unwrap();
+        assert_eq!(hashes.size, 0);
+        assert_eq!(hashes.ed2k.as_deref(), Some("31d6cfe0d16ae931b73c59d7e0c089c0"));
+    }
+
+    #[test]
+    fn regular_files_are_mapped() {
+        let path = std::env::temp_dir().join(format!("anidb-mmap-{}", std::process::id()));
+        fs::write(&path, b"abc").unwrap();
+        let map = unsafe { map_file(&File::open(&path).unwrap()) }.unwrap();
+        assert_eq!(&map[..], b"abc");
+        assert_eq!(crc_hash_with_control(&path, mapped()).unwrap(), "352441c2");
+        drop(map);
+
+        fs::write(&path, b"").unwrap();
+        assert!(unsafe { map_file(&File::open(&path).unwrap()) }.is_none());
+        assert_eq!(crc_hash_with_control(&path, mapped()).unwrap(), "00000000");
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,351 @@
+use std::fs::OpenOptions;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use crcx::{Crc, CRC_32_ISO_HDLC};
+use md5::Md5;
+use sha1::{Sha1, Digest};
+use typed_builder::TypedBuilder;
+
+use crate::ed2k::{Ed2kHasher, Ed2kVariant};
+use crate::progress::HashControl;
+use crate::requests::file::FileMaskResponse;
+
+mod cache;
+#[cfg(feature = "mmap")]
+pub(crate) mod mmap;
+mod parallel;
+
+pub use self::cache::{FileHashCache, FileKey, HashCache};
+pub use self::parallel::{hash_file_async, hash_files, HashOptions};
+
+static CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+/// Which digests to compute in a single pass over the data.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, TypedBuilder)]
+pub struct DigestSelection {
+    #[builder(default)]
+    pub ed2k: bool,
+    #[builder(default)]
+    pub md5: bool,
+    #[builder(default)]
+    pub sha1: bool,
+    #[builder(default)]
+    pub crc32: bool,
+}
+
+impl DigestSelection {
+    pub fn all() -> DigestSelection {
+        DigestSelection {
+            ed2k: true,
+            md5: true,
+            sha1: true,
+            crc32: true,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !(self.ed2k || self.md5 || self.sha1 || self.crc32)
+    }
+}
+
+/// Hex encoded digests of a file, named after the matching `FileMaskResponse`
+/// fields. Digests that were not selected are `None`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct FileHashes {
+    pub size: usize,
+    pub ed2k: Option<String>,
+    /// The variant `ed2k` was computed with.
+    pub ed2k_variant: Ed2kVariant,
+    pub md5: Option<String>,
+    pub sha1: Option<String>,
+    pub crc32: Option<String>,
+}
+
+fn digest_matches(ours: &Option<String>, theirs: &Option<String>) -> Option<bool> {
+    match (ours, theirs) {
+        (Some(ours), Some(theirs)) => Some(ours.eq_ignore_ascii_case(theirs)),
+        _ => None,
+    }
+}
+
+/// Outcome of checking computed hashes against what AniDB returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DigestCheck {
+    Match,
+    /// The size or a digest present on both sides differs.
+    Mismatch,
+    /// No digest was present on both sides, there is nothing to verify.
+    Unknown,
+}
+
+impl FileHashes {
+    /// The part of `selection` that has not been computed here.
+    pub fn missing(&self, selection: DigestSelection) -> DigestSelection {
+        DigestSelection {
+            ed2k: selection.ed2k && self.ed2k.is_none(),
+            md5: selection.md5 && self.md5.is_none(),
+            sha1: selection.sha1 && self.sha1.is_none(),
+            crc32: selection.crc32 && self.crc32.is_none(),
+        }
+    }
+
+    /// Compares every digest present on both sides, ignoring case, along with
+    /// the size if AniDB returned it.
+    pub fn verify(&self, resp: &FileMaskResponse) -> DigestCheck {
+        if matches!(resp.size, Some(size) if size as usize != self.size) {
+            return DigestCheck::Mismatch;
+        }
+        let compared = [
+            digest_matches(&self.ed2k, &resp.ed2k),
+            digest_matches(&self.md5, &resp.md5),
+            digest_matches(&self.sha1, &resp.sha1),
+            digest_matches(&self.crc32, &resp.crc32),
+        ];
+        if compared.contains(&Some(false)) {
+            DigestCheck::Mismatch
+        } else if compared.contains(&Some(true)) {
+            DigestCheck::Match
+        } else {
+            DigestCheck::Unknown
+        }
+    }
+
+    fn merge(&mut self, other: FileHashes) {
+        if self.ed2k.is_none() {
+            self.ed2k = other.ed2k;
+            self.ed2k_variant = other.ed2k_variant;
+        }
+        self.md5 = self.md5.take().or(other.md5);
+        self.sha1 = self.sha1.take().or(other.sha1);
+        self.crc32 = self.crc32.take().or(other.crc32);
+    }
+}
+
+/// Computes the selected digests over data fed through `update` or as a
+/// `std::io::Write`.
+pub struct FileHasher {
+    ed2k: Option<Ed2kHasher>,
+    variant: Ed2kVariant,
+    md5: Option<Md5>,
+    sha1: Option<Sha1>,
+    crc32: Option<crcx::Digest<'static, u32>>,
+    size: usize,
+}
+
+impl FileHasher {
+    pub fn new(selection: DigestSelection) -> FileHasher {
+        FileHasher::with_variant(selection, Ed2kVariant::default())
+    }
+
+    /// Computes the ed2k digest, if selected, with `variant`.
+    pub fn with_variant(selection: DigestSelection, variant: Ed2kVariant) -> FileHasher {
+        FileHasher {
+            ed2k: selection.ed2k.then(|| Ed2kHasher::with_variant(variant)),
+            variant,
+            md5: selection.md5.then(Md5::new),
+            sha1: selection.sha1.then(Sha1::new),
+            crc32: selection.crc32.then(|| CRC32.digest()),
+            size: 0,
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        if let Some(hasher) = &mut self.ed2k {
+            hasher.update(data);
+        }
+        if let Some(hasher) = &mut self.md5 {
+            hasher.update(data);
+        }
+        if let Some(hasher) = &mut self.sha1 {
+            hasher.update(data);
+        }
+        if let Some(hasher) = &mut self.crc32 {
+            hasher.update(data);
+        }
+        self.size += data.len();
+    }
+
+    pub fn finalize(self) -> FileHashes {
+        FileHashes {
+            size: self.size,
+            ed2k: self.ed2k.map(|hasher| hasher.finalize().0),
+            ed2k_variant: self.variant,
+            md5: self.md5.map(|hasher| hex::encode(hasher.finalize())),
+            sha1: self.sha1.map(|hasher| hex::encode(hasher.finalize())),
+            crc32: self.crc32.map(|hasher| format!("{:08x}", hasher.finalize())),
+        }
+    }
+}
+
+impl Write for FileHasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub fn hash_reader<R: Read>(
+    reader: R,
+    selection: DigestSelection,
+    variant: Ed2kVariant,
+    mut control: HashControl<'_>
+) -> io::Result<FileHashes> {
+    let mut hasher = FileHasher::with_variant(selection, variant);
+    control.read_all(reader, |data| hasher.update(data))?;
+    Ok(hasher.finalize())
+}
+
+/// Reads the file once, computing every selected digest.
+pub fn hash_file<P: AsRef<Path>>(p: P, selection: DigestSelection) -> io::Result<FileHashes> {
+    hash_file_with_variant(p, selection, Ed2kVariant::default())
+}
+
+pub fn hash_file_with_variant<P: AsRef<Path>>(
+    p: P,
+    selection: DigestSelection,
+    variant: Ed2kVariant
+) -> io::Result<FileHashes> {
+    hash_file_with_control(p, selection, variant, HashControl::default())
+}
+
+pub fn hash_file_with_control<P: AsRef<Path>>(
+    p: P,
+    selection: DigestSelection,
+    variant: Ed2kVariant,
+    mut control: HashControl<'_>
+) -> io::Result<FileHashes> {
+    let file = OpenOptions::new()
+        .read(true)
+        .open(p)?;
+    control.default_total(file.metadata()?.len());
+    let mut hasher = FileHasher::with_variant(selection, variant);
+    control.read_file(file, |data| hasher.update(data))?;
+    Ok(hasher.finalize())
+}
+
+/// Looks the file up in `cache` first and only computes digests that were not
+/// cached for it in its current state, storing the result. The returned
+/// hashes may include digests cached by earlier runs on top of `selection`.
+/// A cached ed2k digest of another variant than `variant` is recomputed.
+pub fn hash_file_cached<P: AsRef<Path>>(
+    p: P,
+    selection: DigestSelection,
+    variant: Ed2kVariant,
+    cache: &dyn HashCache
+) -> io::Result<FileHashes> {
+    with_cache(p.as_ref(), selection, variant, cache, |path, selection| {
+        hash_file_with_variant(path, selection, variant)
+    })
+}
+
+fn with_cache<F>(
+    path: &Path,
+    selection: DigestSelection,
+    variant: Ed2kVariant,
+    cache: &dyn HashCache,
+    compute: F
+) -> io::Result<FileHashes>
+where F: FnOnce(&Path, DigestSelection) -> io::Result<FileHashes> {
+    let key = FileKey::from_path(path)?;
+    let cached = cache.get(&key)?.map(|mut hashes| {
+        if selection.ed2k && hashes.ed2k_variant != variant {
+            hashes.ed2k = None;
+        }
+        hashes
+    });
+    let missing = cached.as_ref().map_or(selection, |hashes| hashes.missing(selection));
+    if let Some(cached) = cached.clone().filter(|_| missing.is_empty()) {
+        return Ok(cached);
+    }
+    let mut hashes = compute(&key.path, missing)?;
+    if let Some(cached) = cached {
+        hashes.merge(cached);
+    }
+    cache.store(&key, &hashes)?;
+    Ok(hashes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn digests_of_abc() {
+        let red = Ed2kVariant::Red;
+        let hashes = hash_reader(&b"abc"[..], DigestSelection::all(), red, HashControl::new())
+            .unwrap();
+        assert_eq!(hashes, FileHashes {
+            size: 3,
+            ed2k: Some(String::from("a448017aaf21d8525fc10ae87aa6729d")),
+            ed2k_variant: Ed2kVariant::Red,
+            md5: Some(String::from("900150983cd24fb0d6963f7d28e17f72")),
+            sha1: Some(String::from("a9993e364706816aba3e25717850c26c9cd0d89d")),
+            crc32: Some(String::from("352441c2")),
+        });
+
+        let selection = DigestSelection::builder().crc32(true).build();
+        let hashes = hash_reader(&b"abc"[..], selection, red, HashControl::new()).unwrap();
+        assert_eq!(hashes.crc32.as_deref(), Some("352441c2"));
+        assert_eq!((hashes.ed2k, hashes.md5, hashes.sha1), (None, None, None));
+    }
+
+    #[test]
+    fn ed2k_variants() {
+        use crate::ed2k::CHUNK_SIZE;
+
+        let data = vec![0u8; CHUNK_SIZE];
+        let selection = DigestSelection::builder().ed2k(true).build();
+        let digest = |variant| {
+            let mut hasher = FileHasher::with_variant(selection, variant);
+            hasher.update(&data);
+            hasher.finalize().ed2k.unwrap()
+        };
+        for variant in [Ed2kVariant::Red, Ed2kVariant::Blue] {
+            let mut expected = Ed2kHasher::with_variant(variant);
+            expected.update(&data);
+            assert_eq!(digest(variant), expected.finalize().0, "{:?}", variant);
+        }
+        assert_ne!(digest(Ed2kVariant::Red), digest(Ed2kVariant::Blue));
+
+        let mut hasher = FileHasher::new(selection);
+        hasher.update(&data);
+        assert_eq!(hasher.finalize().ed2k.unwrap(), digest(Ed2kVariant::Red));
+    }
+
+    #[test]
+    fn compares_against_file_response() {
+        let red = Ed2kVariant::Red;
+        let hashes = hash_reader(&b"abc"[..], DigestSelection::all(), red, HashControl::new())
+            .unwrap();
+        let mut resp = FileMaskResponse {
+            size: Some(3),
+            crc32: Some(String::from("352441C2")),
+            ..Default::default()
+        };
+        assert_eq!(hashes.verify(&resp), DigestCheck::Match);
+
+        resp.md5 = Some(String::from("00000000000000000000000000000000"));
+        assert_eq!(hashes.verify(&resp), DigestCheck::Mismatch);
+
+        resp.md5 = None;
+        resp.size = Some(4);
+        assert_eq!(hashes.verify(&resp), DigestCheck::Mismatch);
+
+        // nothing in common to compare
+        assert_eq!(hashes.verify(&FileMaskResponse::default()), DigestCheck::Unknown);
+        let resp = FileMaskResponse { size: Some(3), ..Default::default() };
+        assert_eq!(hashes.verify(&resp), DigestCheck::Unknown);
+        let crc_only = hash_reader(
+            &b"abc"[..],
+            DigestSelection::builder().crc32(true).build(),
+            red,
+            HashControl::new()
+        ).unwrap();
+        let resp = FileMaskResponse { md5: Some(String::from("md5")), ..Default::default() };
+        assert_eq!(crc_only.verify(&resp), DigestCheck::Unknown);
+    }
+}
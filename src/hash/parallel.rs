@@ -0,0 +1,366 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use futures_util::stream::{self, Stream, StreamExt};
+use md4::{Md4, Digest};
+use tokio::runtime::Handle;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::task::JoinHandle;
+use typed_builder::TypedBuilder;
+
+use crate::ed2k::{self, Ed2kVariant, CHUNK_SIZE};
+use crate::progress::{CancellationToken, HashControl, Progress};
+use super::{with_cache, DigestSelection, FileHasher, FileHashes, HashCache};
+
+#[derive(Clone, TypedBuilder)]
+pub struct HashOptions {
+    #[builder(default = DigestSelection::all())]
+    pub selection: DigestSelection,
+    /// Chunk boundary handling of the ed2k digest, if selected.
+    #[builder(default)]
+    pub variant: Ed2kVariant,
+    /// Number of files hashed at the same time.
+    #[builder(default = 2)]
+    pub files: usize,
+    /// Number of ed2k chunks of a single file hashed at the same time, each
+    /// one holds a chunk sized buffer while in flight.
+    #[builder(default = 4)]
+    pub chunks: usize,
+    /// Stops every file still being hashed, those yield a cancelled error.
+    #[builder(default)]
+    pub cancel: CancellationToken,
+    #[builder(default, setter(strip_option))]
+    pub progress: Option<UnboundedSender<(PathBuf, Progress)>>,
+    /// Checked before hashing each file, computed hashes are stored in it.
+    #[builder(default, setter(strip_option))]
+    pub cache: Option<Arc<dyn HashCache>>,
+    #[cfg(feature = "mmap")]
+    #[builder(default, setter(skip))]
+    mmap: bool,
+}
+
+impl fmt::Debug for HashOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HashOptions")
+            .field("selection", &self.selection)
+            .field("variant", &self.variant)
+            .field("files", &self.files)
+            .field("chunks", &self.chunks)
+            .field("cancel", &self.cancel)
+            .field("progress", &self.progress)
+            .field("cache", &self.cache.is_some())
+            .finish()
+    }
+}
+
+impl HashOptions {
+    /// Maps regular, non-empty files into memory instead of reading them.
+    ///
+    /// # Safety
+    ///
+    /// None of the files may be truncated, by this or any other process,
+    /// while they are hashed. Reading a mapped page past the new end of a file
+    /// raises SIGBUS.
+    #[cfg(feature = "mmap")]
+    pub unsafe fn allow_mmap(mut self) -> HashOptions {
+        self.mmap = true;
+        self
+    }
+}
+
+impl Default for HashOptions {
+    fn default() -> HashOptions {
+        HashOptions::builder().build()
+    }
+}
+
+fn join_error(e: tokio::task::JoinError) -> io::Error {
+    io::Error::other(e)
+}
+
+// An ed2k chunk, shared between the reading thread and its hashing task.
+#[derive(Clone)]
+enum Chunk {
+    Read(Arc<Vec<u8>>),
+    #[cfg(feature = "mmap")]
+    Mapped(Arc<memmap2::Mmap>, std::ops::Range<usize>),
+}
+
+impl Deref for Chunk {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Chunk::Read(data) => data,
+            #[cfg(feature = "mmap")]
+            Chunk::Mapped(map, range) => &map[range.clone()],
+        }
+    }
+}
+
+enum Chunks {
+    Read(File),
+    #[cfg(feature = "mmap")]
+    Mapped(Arc<memmap2::Mmap>, usize),
+}
+
+impl Chunks {
+    #[cfg_attr(not(feature = "mmap"), allow(unused_variables))]
+    fn new(file: File, options: &HashOptions) -> Chunks {
+        #[cfg(feature = "mmap")]
+        if options.mmap {
+            // SAFETY: the caller of `HashOptions::allow_mmap` guarantees the
+            // file is not truncated while it is hashed
+            if let Some(map) = unsafe { super::mmap::map_file(&file) } {
+                return Chunks::Mapped(Arc::new(map), 0);
+            }
+        }
+        Chunks::Read(file)
+    }
+
+    /// Returns an empty chunk at the end of the file.
+    fn next_chunk(&mut self) -> io::Result<Chunk> {
+        match self {
+            Chunks::Read(file) => {
+                let mut buf = Vec::with_capacity(CHUNK_SIZE);
+                file.by_ref().take(CHUNK_SIZE as u64).read_to_end(&mut buf)?;
+                Ok(Chunk::Read(Arc::new(buf)))
+            }
+            #[cfg(feature = "mmap")]
+            Chunks::Mapped(map, offset) => {
+                let start = *offset;
+                *offset = (start + CHUNK_SIZE).min(map.len());
+                Ok(Chunk::Mapped(map.clone(), start..*offset))
+            }
+        }
+    }
+}
+
+// Runs on the blocking pool. The file is read sequentially, feeding the other
+// digests on this thread while every ed2k chunk is hashed on its own task.
+fn hash_chunked(
+    path: &Path,
+    selection: DigestSelection,
+    options: &HashOptions,
+    handle: &Handle
+) -> io::Result<FileHashes> {
+    let file = File::open(path)?;
+    let mut control = HashControl::new()
+        .total(file.metadata()?.len())
+        .cancel_with(options.cancel.clone());
+    if let Some(progress) = options.progress.clone() {
+        let path = path.to_path_buf();
+        // the receiver going away only means nobody is watching anymore
+        control = control.on_progress(move |p| {
+            let _ = progress.send((path.clone(), p));
+        });
+    }
+    control.check()?;
+    let mut chunks = Chunks::new(file, options);
+    let mut hasher = FileHasher::with_variant(
+        DigestSelection { ed2k: false, ..selection },
+        options.variant
+    );
+    let mut pending: VecDeque<JoinHandle<Vec<u8>>> = VecDeque::new();
+    let mut hashlist = Vec::new();
+    loop {
+        let chunk = chunks.next_chunk()?;
+        if chunk.is_empty() {
+            break;
+        }
+        control.advance(chunk.len())?;
+        if selection.ed2k {
+            if pending.len() >= options.chunks.max(1) {
+                let task = pending.pop_front().unwrap();
+                hashlist.extend(handle.block_on(task).map_err(join_error)?);
+            }
+            let chunk = chunk.clone();
+            pending.push_back(handle.spawn_blocking(move || Md4::digest(&chunk).to_vec()));
+        }
+        hasher.update(&chunk);
+    }
+    for task in pending {
+        hashlist.extend(handle.block_on(task).map_err(join_error)?);
+    }
+    control.advance(0)?;
+
+    let mut hashes = hasher.finalize();
+    if selection.ed2k {
+        hashes.ed2k = Some(ed2k::ed2k_from_chunk_hashes(hashlist, hashes.size, options.variant));
+    }
+    Ok(hashes)
+}
+
+/// Hashes a file on the blocking thread pool, ed2k chunks are hashed in
+/// parallel.
+pub async fn hash_file_async<P: AsRef<Path>>(p: P, options: HashOptions) -> io::Result<FileHashes> {
+    let path = p.as_ref().to_path_buf();
+    let handle = Handle::current();
+    tokio::task::spawn_blocking(move || match &options.cache {
+        Some(cache) => {
+            let (selection, variant) = (options.selection, options.variant);
+            with_cache(&path, selection, variant, &**cache, |path, selection| {
+                hash_chunked(path, selection, &options, &handle)
+            })
+        }
+        None => hash_chunked(&path, options.selection, &options, &handle),
+    })
+        .await
+        .map_err(join_error)?
+}
+
+/// Hashes up to `options.files` files at the same time, yielding each result
+/// as soon as it is done, so not necessarily in the order given.
+pub fn hash_files<I>(
+    paths: I,
+    options: HashOptions
+) -> impl Stream<Item = (PathBuf, io::Result<FileHashes>)>
+where I: IntoIterator,
+      I::Item: AsRef<Path> {
+    let files = options.files.max(1);
+    stream::iter(paths)
+        .map(move |path| {
+            let path = path.as_ref().to_path_buf();
+            let options = options.clone();
+            async move {
+                let hashes = hash_file_async(&path, options).await;
+                (path, hashes)
+            }
+        })
+        .buffer_unordered(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::fs;
+    use crate::hash::hash_file;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn matches_sequential_hashing() {
+        let sizes = [0, 3, CHUNK_SIZE, 2 * CHUNK_SIZE + 5];
+        let paths: Vec<PathBuf> = sizes.iter()
+            .map(|size| {
+                let path = std::env::temp_dir()
+                    .join(format!("anidb-hash-{}-{}", std::process::id(), size));
+                let data: Vec<u8> = (0..*size).map(|i| (i % 251) as u8).collect();
+                fs::write(&path, data).unwrap();
+                path
+            })
+            .collect();
+
+        let options = HashOptions::builder().files(2).chunks(2).build();
+        let results: HashMap<PathBuf, FileHashes> = hash_files(&paths, options)
+            .map(|(path, hashes)| (path, hashes.unwrap()))
+            .collect()
+            .await;
+
+        assert_eq!(results.len(), paths.len());
+        for path in &paths {
+            let expected = hash_file(path, DigestSelection::all()).unwrap();
+            assert_eq!(results[path], expected, "{:?}", path);
+        }
+
+        // SAFETY: nothing else touches the files until they are removed below
+        #[cfg(feature = "mmap")]
+        for path in &paths {
+            let options = unsafe { HashOptions::default().allow_mmap() };
+            let mapped = hash_file_async(path, options).await.unwrap();
+            assert_eq!(mapped, results[path], "{:?}", path);
+        }
+        for path in &paths {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn ed2k_variants() {
+        use crate::ed2k::ed2k_hash_with_variant;
+        use crate::hash::{hash_file_cached, hash_file_with_variant, FileHashCache};
+
+        // an exact multiple of the chunk size, where the variants differ
+        let path = std::env::temp_dir()
+            .join(format!("anidb-hash-variant-{}", std::process::id()));
+        let cache_path = path.with_extension("cache");
+        fs::write(&path, vec![0u8; 2 * CHUNK_SIZE]).unwrap();
+        let cache = FileHashCache::open(&cache_path).unwrap();
+
+        let selection = DigestSelection::builder().ed2k(true).build();
+        let mut digests = Vec::new();
+        for variant in [Ed2kVariant::Red, Ed2kVariant::Blue] {
+            let options = HashOptions::builder()
+                .selection(selection)
+                .variant(variant)
+                .build();
+            let hashes = hash_file_async(&path, options).await.unwrap();
+            let expected = ed2k_hash_with_variant(&path, variant).unwrap().0;
+            assert_eq!(hashes.ed2k.as_deref(), Some(expected.as_str()), "{:?}", variant);
+            assert_eq!(hashes.ed2k_variant, variant);
+            assert_eq!(hash_file_with_variant(&path, selection, variant).unwrap(), hashes);
+            // the cache holds the other variant after the first round
+            assert_eq!(hash_file_cached(&path, selection, variant, &cache).unwrap(), hashes);
+            digests.push(expected);
+        }
+        assert_ne!(digests[0], digests[1]);
+        fs::remove_file(path).unwrap();
+        fs::remove_file(cache_path).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn progress_and_cancellation() {
+        let path = std::env::temp_dir()
+            .join(format!("anidb-hash-progress-{}", std::process::id()));
+        fs::write(&path, vec![0u8; 3 * CHUNK_SIZE]).unwrap();
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let options = HashOptions::builder().progress(tx).build();
+        hash_file_async(&path, options).await.unwrap();
+        let mut last = None;
+        while let Some((progress_path, progress)) = rx.recv().await {
+            assert_eq!(progress_path, path);
+            last = Some(progress);
+        }
+        assert_eq!(last.unwrap().bytes, 3 * CHUNK_SIZE as u64);
+        assert_eq!(last.unwrap().total, Some(3 * CHUNK_SIZE as u64));
+
+        let options = HashOptions::builder().build();
+        options.cancel.cancel();
+        let err = hash_file_async(&path, options).await.unwrap_err();
+        assert!(crate::progress::is_cancelled(&err));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn uses_cache() {
+        use crate::hash::{FileHashCache, FileKey};
+
+        let path = std::env::temp_dir()
+            .join(format!("anidb-hash-cached-{}", std::process::id()));
+        let cache_path = path.with_extension("cache");
+        fs::write(&path, b"abc").unwrap();
+
+        let cache = Arc::new(FileHashCache::open(&cache_path).unwrap());
+        let options = HashOptions::builder().cache(cache.clone() as Arc<dyn HashCache>).build();
+        let hashes = hash_file_async(&path, options.clone()).await.unwrap();
+        let key = FileKey::from_path(&path).unwrap();
+        assert_eq!(cache.get(&key).unwrap(), Some(hashes.clone()));
+
+        let fake = FileHashes { md5: Some(String::from("cached")), ..hashes };
+        cache.store(&key, &fake).unwrap();
+        assert_eq!(hash_file_async(&path, options).await.unwrap(), fake);
+
+        fs::remove_file(path).unwrap();
+        fs::remove_file(cache_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn missing_file() {
+        let err = hash_file_async("/nonexistent/anidb-hash", HashOptions::default()).await;
+        assert_eq!(err.unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -2,6 +2,10 @@
 pub mod ed2k;
 #[cfg(feature = "crc")]
 pub mod crc;
+#[cfg(feature = "hash")]
+pub mod hash;
+#[cfg(any(feature = "ed2k", feature = "crc"))]
+pub mod progress;
 
 mod client;
 mod cache;
@@ -10,12 +14,34 @@ mod mask;
 #[macro_use]
 mod errors;
 mod requests;
+mod ser;
+mod link;
 
 pub use async_trait::async_trait;
 
 pub use crate::client::AniDbClient;
-pub use crate::cache::AniDbCache;
+pub use crate::cache::{
+    AniDbCache,
+    CacheKey,
+    CachePolicy,
+    CacheStats,
+    CachedReply,
+    InMemoryCache,
+    ReplyClass,
+};
+#[cfg(feature = "fs-cache")]
+pub use crate::cache::{
+    FsCache,
+    FsCacheError,
+};
+#[cfg(feature = "sqlite")]
+pub use crate::cache::{
+    SqliteCache,
+    SqliteCacheError,
+};
 pub use crate::errors::AniDbError;
+pub use crate::link::Ed2kLink;
+pub use crate::mask::FieldValue;
 pub use crate::requests::{
     auth::{
         AuthRequest,
@@ -25,18 +51,33 @@ pub use crate::requests::{
         AnimeRequest,
         AnimeRequestError,
         AnimeRequestFields,
+        AnimeRequestField,
         AnimeResponse,
     },
     file::{
         FileRequest,
         FileResponse,
         FileMask,
+        FileMaskField,
         AnimeMask,
+        AnimeMaskField,
         FileMaskResponse,
         AnimeMaskResponse,
         FileRequestError,
     },
-    types::EpNo,
+    types::{
+        EpNo,
+        EpNoRange,
+        Timestamp,
+        PartialDate,
+        DateFlags,
+        YearRange,
+        FileState,
+        CrcStatus,
+        Censorship,
+        AnimeType,
+        RelationType,
+    },
 };
 
 const ANIDB_ADDR: (&str, u16) = ("api.anidb.net", 9000);
@@ -0,0 +1,206 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::requests::file::FileRequest;
+
+/// An `ed2k://|file|name|size|hash|/` link, optionally carrying the AICH root
+/// hash (`h=`) and a list of `host:port` sources.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Ed2kLink {
+    pub name: String,
+    pub size: usize,
+    /// Lowercase hex ed2k hash.
+    pub hash: String,
+    pub aich: Option<String>,
+    pub sources: Vec<String>,
+}
+
+impl Ed2kLink {
+    /// Builds a link from a file name and the output of `ed2k_hash`.
+    pub fn new<S: Into<String>>(name: S, (hash, size): (String, usize)) -> Ed2kLink {
+        Ed2kLink {
+            name: name.into(),
+            size,
+            hash: hash.to_ascii_lowercase(),
+            aich: None,
+            sources: Vec::new(),
+        }
+    }
+}
+
+fn link_error(message: &str, input: &str) -> crate::AniDbError {
+    crate::AniDbError::DecodeError(format!("{}: {}", message, input))
+}
+
+// names are percent-encoded, at least `%` and `|` have to be for the link
+// to parse again, spaces and control characters are encoded for safe pasting
+fn encode_name(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    for c in name.chars() {
+        match c {
+            '%' | '|' | ' ' => out.push_str(&format!("%{:02X}", c as u32)),
+            c if c.is_ascii_control() => out.push_str(&format!("%{:02X}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn decode_name(input: &str) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = input.get(i + 1..i + 3)?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+fn is_hash(input: &str) -> bool {
+    input.len() == 32 && input.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+impl fmt::Display for Ed2kLink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ed2k://|file|{}|{}|{}|", encode_name(&self.name), self.size, self.hash)?;
+        if let Some(aich) = &self.aich {
+            write!(f, "h={}|", aich)?;
+        }
+        f.write_str("/")?;
+        if !self.sources.is_empty() {
+            write!(f, "|sources,{}|/", self.sources.join(","))?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Ed2kLink {
+    type Err = crate::AniDbError;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let trimmed = input.trim();
+        let rest = trimmed.get(..7)
+            .filter(|scheme| scheme.eq_ignore_ascii_case("ed2k://"))
+            .map(|_| &trimmed[7..])
+            .ok_or_else(|| link_error("Not an ed2k link", input))?;
+        let mut parts = rest.split('|');
+        if parts.next() != Some("") || parts.next() != Some("file") {
+            return Err(link_error("Not an ed2k file link", input));
+        }
+        let name = parts.next()
+            .and_then(decode_name)
+            .filter(|name| !name.is_empty())
+            .ok_or_else(|| link_error("Invalid ed2k link name", input))?;
+        let size = parts.next()
+            .and_then(|size| size.parse().ok())
+            .ok_or_else(|| link_error("Invalid ed2k link size", input))?;
+        let hash = parts.next()
+            .filter(|hash| is_hash(hash))
+            .ok_or_else(|| link_error("Invalid ed2k link hash", input))?
+            .to_ascii_lowercase();
+
+        let mut link = Ed2kLink {
+            name,
+            size,
+            hash,
+            aich: None,
+            sources: Vec::new(),
+        };
+        // other optional parts, like `p=` part hashes or `s=` http sources,
+        // are skipped
+        for part in parts {
+            if let Some(aich) = part.strip_prefix("h=") {
+                link.aich = Some(aich.to_string());
+            } else if let Some(sources) = part.strip_prefix("sources,") {
+                link.sources.extend(
+                    sources.split(',')
+                        .filter(|source| !source.is_empty())
+                        .map(String::from)
+                );
+            }
+        }
+        Ok(link)
+    }
+}
+
+impl From<Ed2kLink> for FileRequest {
+    fn from(link: Ed2kLink) -> FileRequest {
+        FileRequest::SizeEd2k(link.size, link.hash, None, None)
+    }
+}
+
+impl From<&Ed2kLink> for FileRequest {
+    fn from(link: &Ed2kLink) -> FileRequest {
+        FileRequest::SizeEd2k(link.size, link.hash.clone(), None, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "a448017aaf21d8525fc10ae87aa6729d";
+
+    #[test]
+    fn format_and_parse() {
+        let link = Ed2kLink::new("[Group] Show - 01 (100%).mkv", (HASH.to_uppercase(), 3));
+        let text = link.to_string();
+        assert_eq!(
+            text,
+            format!("ed2k://|file|[Group]%20Show%20-%2001%20(100%25).mkv|3|{}|/", HASH)
+        );
+        assert_eq!(text.parse::<Ed2kLink>().unwrap(), link);
+
+        let link = Ed2kLink {
+            aich: Some(String::from("ZBQZMDOXI2BUI3DBZV6YNVHSIP5J2HAX")),
+            sources: vec![String::from("10.0.0.1:4662"), String::from("example.org:4242")],
+            ..link
+        };
+        assert_eq!(link.to_string().parse::<Ed2kLink>().unwrap(), link);
+    }
+
+    #[test]
+    fn parse_optional_parts() {
+        let text = format!(
+            "ed2k://|file|Show%2001.mkv|734003200|{}|p={}:{}|h=AICHROOT|s=http://example.org/a|/\
+             |sources,10.0.0.1:4662,10.0.0.2:4662|/",
+            HASH.to_uppercase(), HASH, HASH
+        );
+        let link: Ed2kLink = text.parse().unwrap();
+        assert_eq!(link.name, "Show 01.mkv");
+        assert_eq!(link.size, 734003200);
+        assert_eq!(link.hash, HASH);
+        assert_eq!(link.aich.as_deref(), Some("AICHROOT"));
+        assert_eq!(link.sources, vec!["10.0.0.1:4662", "10.0.0.2:4662"]);
+    }
+
+    #[test]
+    fn parse_errors() {
+        for text in [
+            "http://|file|a|1|a448017aaf21d8525fc10ae87aa6729d|/",
+            "ed2k://|server|1.2.3.4|4661|/",
+            "ed2k://|file||1|a448017aaf21d8525fc10ae87aa6729d|/",
+            "ed2k://|file|a|-1|a448017aaf21d8525fc10ae87aa6729d|/",
+            "ed2k://|file|a|1|a448017aaf21d8525fc10ae87aa672|/",
+            "ed2k://|file|a%2|1|a448017aaf21d8525fc10ae87aa6729d|/",
+        ] {
+            assert!(text.parse::<Ed2kLink>().is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn into_file_request() {
+        let link = Ed2kLink::new("a.mkv", (String::from(HASH), 3));
+        match FileRequest::from(link) {
+            FileRequest::SizeEd2k(3, hash, None, None) => assert_eq!(hash, HASH),
+            _ => panic!("expected a size+ed2k request"),
+        }
+    }
+}
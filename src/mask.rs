@@ -5,143 +5,231 @@ macro_rules! next_or_decode_error {
     }
 }
 
+use std::fmt;
+use crate::requests::types::{
+    AnimeType,
+    DateFlags,
+    EpNo,
+    EpNoRange,
+    FileState,
+    PartialDate,
+    RelationType,
+    Timestamp,
+    YearRange,
+};
+
 pub trait FieldDecoder {
-    fn decode_field<'a>(input: &'a str) -> Result<Self, crate::AniDbError>
+    fn decode_field(input: &str) -> Result<Self, crate::AniDbError>
         where Self: Sized;
 }
 
 impl FieldDecoder for i32 {
-    fn decode_field<'a>(input: &'a str) -> Result<Self, crate::AniDbError>
+    fn decode_field(input: &str) -> Result<Self, crate::AniDbError>
     where Self: Sized {
         Ok(input.parse()?)
     }
 }
 
 impl FieldDecoder for i16 {
-    fn decode_field<'a>(input: &'a str) -> Result<Self, crate::AniDbError>
+    fn decode_field(input: &str) -> Result<Self, crate::AniDbError>
     where Self: Sized {
         Ok(input.parse()?)
     }
 }
 
 impl FieldDecoder for i64 {
-    fn decode_field<'a>(input: &'a str) -> Result<Self, crate::AniDbError>
+    fn decode_field(input: &str) -> Result<Self, crate::AniDbError>
     where Self: Sized {
         Ok(input.parse()?)
     }
 }
 
 impl FieldDecoder for String {
-    fn decode_field<'a>(input: &'a str) -> Result<Self, crate::AniDbError>
+    fn decode_field(input: &str) -> Result<Self, crate::AniDbError>
     where Self: Sized {
-        Ok(input.to_string())
+        Ok(unescape(input))
     }
 }
 
 impl FieldDecoder for bool {
-    fn decode_field<'a>(input: &'a str) -> Result<Self, crate::AniDbError>
+    fn decode_field(input: &str) -> Result<Self, crate::AniDbError>
     where Self: Sized {
         Ok(<i16 as FieldDecoder>::decode_field(input)? == 1)
     }
 }
 
 impl<T> FieldDecoder for Vec<T> where T: FieldDecoder {
-    fn decode_field<'a>(input: &'a str) -> Result<Self, crate::AniDbError>
+    fn decode_field(input: &str) -> Result<Self, crate::AniDbError>
     where Self: Sized {
-        Ok(input.split(",")
+        if input.is_empty() {
+            return Ok(Vec::new());
+        }
+        input.split(",")
             .map(|part| <T as FieldDecoder>::decode_field(part))
-            .collect::<Result<Vec<T>, _>>()?)
+            .collect::<Result<Vec<T>, _>>()
     }
 }
 
-macro_rules! decode_field {
-    ($iter:ident, $resp:ident, $field:tt, $ty:ty) => {
-        let $field = <$ty as crate::mask::FieldDecoder>::decode_field(next_or_decode_error!($iter)?)?;
-        $resp.$field = Some($field);
-    };
-    ($iter:ident, $mask:ident, $resp:ident, $field:tt, $ty:ty) => {
-        if $mask.$field {
-            let $field = <$ty as crate::mask::FieldDecoder>::decode_field(next_or_decode_error!($iter)?)?;
-            $resp.$field = Some($field);
+/// A decoded field of any supported type, see the `get` method generated on
+/// mask responses.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub enum FieldValue {
+    Int(i64),
+    Bool(bool),
+    String(String),
+    EpNo(EpNo),
+    EpNoRange(EpNoRange),
+    Timestamp(Timestamp),
+    PartialDate(PartialDate),
+    DateFlags(DateFlags),
+    YearRange(YearRange),
+    FileState(FileState),
+    AnimeType(AnimeType),
+    RelationType(RelationType),
+    List(Vec<FieldValue>),
+}
+
+impl fmt::Display for FieldValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FieldValue::Int(v) => write!(f, "{}", v),
+            FieldValue::Bool(v) => write!(f, "{}", v),
+            FieldValue::String(v) => f.write_str(v),
+            FieldValue::EpNo(v) => write!(f, "{}", v),
+            FieldValue::EpNoRange(v) => write!(f, "{}", v),
+            FieldValue::Timestamp(v) => write!(f, "{}", v.as_secs()),
+            FieldValue::PartialDate(v) => write!(f, "{}", v),
+            FieldValue::DateFlags(v) => write!(f, "{}", v.0),
+            FieldValue::YearRange(v) => write!(f, "{}", v),
+            FieldValue::FileState(v) => write!(f, "{}", v.bits()),
+            FieldValue::AnimeType(v) => write!(f, "{}", v),
+            FieldValue::RelationType(v) => write!(f, "{}", v),
+            FieldValue::List(values) => {
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                Ok(())
+            }
         }
     }
 }
 
-macro_rules! generate_mask {
-    ($builder:ident, $resp:ident, $decode_err:ident, $($($offset:expr, $ty:ty, $name:ident),*)&*;$($ety:ty, $ename:ident)*) => {
-        #[derive(Clone, Default, ::typed_builder::TypedBuilder)]
-        pub struct $builder {
-            $(
-                $(
-                    #[builder(default)]
-                    $name: bool,
-                )*
-            )*
-        }
+pub trait ToFieldValue {
+    fn to_field_value(&self) -> FieldValue;
+}
 
-        impl $builder {
-            pub fn all() -> $builder {
-                $builder {
-                    $(
-                        $(
-                            $name: true,
-                        )*
-                    )*
-                }
-            }
-            pub fn none() -> $builder {
-                $builder {
-                    $(
-                        $(
-                            $name: false,
-                        )*
-                    )*
+macro_rules! impl_to_field_value {
+    ($($ty:ty => $variant:ident $(as $cast:ty)?),*) => {
+        $(
+            impl ToFieldValue for $ty {
+                fn to_field_value(&self) -> FieldValue {
+                    FieldValue::$variant(self.clone() $(as $cast)?)
                 }
             }
-        }
+        )*
+    }
+}
 
-        impl ::serde::Serialize for $builder {
-            fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-            where S: ::serde::Serializer {
-                let mut bytes = Vec::new();
-                $(
-                    let mut b = 0;
-                    $(
-                        b += (self.$name as u8).overflowing_shl($offset).0;
-                    )*
-                    bytes.push(b);
-                )*
-                let s = ::hex::encode(bytes);
-                serializer.serialize_str(&s)
-            }
-        }
+impl_to_field_value!(
+    i16 => Int as i64,
+    i32 => Int as i64,
+    i64 => Int,
+    bool => Bool,
+    String => String,
+    EpNo => EpNo,
+    EpNoRange => EpNoRange,
+    Timestamp => Timestamp,
+    PartialDate => PartialDate,
+    DateFlags => DateFlags,
+    YearRange => YearRange,
+    FileState => FileState,
+    AnimeType => AnimeType,
+    RelationType => RelationType
+);
 
-        #[derive(Default, Debug)]
-        pub struct $resp {
-            $(
-                $(
-                    pub $name: Option<$ty>,
-                )*
-            )*
-            $(
-                pub $ename: Option<$ety>,
-            )*
-        }
+impl<T> ToFieldValue for Vec<T> where T: ToFieldValue {
+    fn to_field_value(&self) -> FieldValue {
+        FieldValue::List(self.iter().map(ToFieldValue::to_field_value).collect())
+    }
+}
 
-        impl $builder {
-            fn decode_response<'a>(
-                &self,
-                field_iter: &mut impl Iterator<Item = &'a str>
-            ) -> Result<$resp, $decode_err> {
-                let mut resp = $resp::default();
-                $(
-                    $(
-                        decode_field!(field_iter, self, resp, $name, $ty);
-                    )*
-                )*
-                Ok(resp)
-            }
+/// Reverses AniDB's content escaping: newlines are sent as `<br />` and
+/// apostrophes as backticks (a literal `'` is reserved as list separator).
+pub fn unescape(input: &str) -> String {
+    input.replace("<br />", "\n").replace('`', "'")
+}
+
+/// Decodes a `'` separated list, as used by AniDB for name lists.
+pub fn decode_list<T>(input: &str) -> Result<Vec<T>, crate::AniDbError>
+where T: FieldDecoder {
+    if input.is_empty() {
+        return Ok(Vec::new());
+    }
+    input.split('\'')
+        .map(|part| <T as FieldDecoder>::decode_field(part))
+        .collect()
+}
+
+/// Moves the values of the `stored` fields out of `values`, keeping those
+/// `wanted` in `selected`. Used to answer a request from a reply fetched with
+/// a superset of its mask, `None` if there are fewer values than fields.
+pub(crate) fn select_fields<'a, F>(
+    values: &mut impl Iterator<Item = &'a str>,
+    stored: impl Iterator<Item = F>,
+    wanted: impl Fn(F) -> bool,
+    selected: &mut Vec<&'a str>,
+) -> Option<()> {
+    for field in stored {
+        let value = values.next()?;
+        if wanted(field) {
+            selected.push(value);
         }
+    }
+    Some(())
+}
+
+macro_rules! decode_field {
+    ($iter:ident, $resp:ident, $field:tt, $ty:ty) => {
+        let $field = <$ty as crate::mask::FieldDecoder>::decode_field(next_or_decode_error!($iter)?)?;
+        $resp.$field = Some($field);
+    };
+    ($iter:ident, $resp:ident, $field:tt, $ty:ty => $decoder:ident) => {
+        let $field: $ty = $decoder(next_or_decode_error!($iter)?)?;
+        $resp.$field = Some($field);
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unescapes_content() {
+        assert_eq!(unescape("line<br />next"), "line\nnext");
+        assert_eq!(unescape("plain"), "plain");
+        assert_eq!(unescape("Tom`s Diner"), "Tom's Diner");
+        assert_eq!(<String as FieldDecoder>::decode_field("a`b<br />c").unwrap(), "a'b\nc");
+    }
+
+    #[test]
+    fn decodes_lists() {
+        assert_eq!(
+            decode_list::<String>("Tensura'Slime`s Life").unwrap(),
+            vec!["Tensura", "Slime's Life"]
+        );
+        assert_eq!(decode_list::<i32>("1'2'3").unwrap(), vec![1, 2, 3]);
+        assert_eq!(decode_list::<String>("").unwrap(), Vec::<String>::new());
+        assert!(decode_list::<i32>("1'x").is_err());
+    }
 
+    #[test]
+    fn decodes_comma_lists() {
+        assert_eq!(<Vec<i32> as FieldDecoder>::decode_field("2607,2609").unwrap(), vec![2607, 2609]);
+        assert_eq!(<Vec<i32> as FieldDecoder>::decode_field("").unwrap(), Vec::<i32>::new());
+        assert!(<Vec<i32> as FieldDecoder>::decode_field("1,").is_err());
     }
 }
@@ -0,0 +1,220 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+const BUFFER_SIZE: usize = 1_048_576;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Progress {
+    pub bytes: u64,
+    /// Size of the input, when known up front.
+    pub total: Option<u64>,
+    pub elapsed: Duration,
+}
+
+impl Progress {
+    /// Average bytes per second since hashing started.
+    pub fn throughput(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            self.bytes as f64 / secs
+        } else {
+            0.0
+        }
+    }
+
+    pub fn fraction(&self) -> Option<f64> {
+        match self.total {
+            Some(0) => Some(1.0),
+            Some(total) => Some(self.bytes as f64 / total as f64),
+            None => None,
+        }
+    }
+}
+
+/// Shared flag to abort hashing from another thread, clones cancel together.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Error wrapped in the `io::Error` returned by a cancelled hasher.
+#[derive(Debug, thiserror::Error)]
+#[error("hashing was cancelled")]
+pub struct Cancelled;
+
+pub fn is_cancelled(err: &io::Error) -> bool {
+    err.get_ref().is_some_and(|e| e.is::<Cancelled>())
+}
+
+/// Progress callback and cancellation token for a single hashing run.
+#[derive(Default)]
+pub struct HashControl<'a> {
+    progress: Option<Box<dyn FnMut(Progress) + 'a>>,
+    cancel: Option<CancellationToken>,
+    total: Option<u64>,
+    bytes: u64,
+    started: Option<Instant>,
+    #[cfg(feature = "mmap")]
+    mmap: bool,
+}
+
+impl<'a> HashControl<'a> {
+    pub fn new() -> HashControl<'a> {
+        HashControl::default()
+    }
+
+    /// Called after every read, and once more when the input is exhausted.
+    pub fn on_progress<F>(mut self, f: F) -> HashControl<'a>
+    where F: FnMut(Progress) + 'a {
+        self.progress = Some(Box::new(f));
+        self
+    }
+
+    pub fn cancel_with(mut self, token: CancellationToken) -> HashControl<'a> {
+        self.cancel = Some(token);
+        self
+    }
+
+    /// Sets the total reported in `Progress`, the file based functions fill it
+    /// in from the file size.
+    pub fn total(mut self, total: u64) -> HashControl<'a> {
+        self.total = Some(total);
+        self
+    }
+
+    /// Lets the path based functions map regular, non-empty files into memory
+    /// instead of reading them.
+    ///
+    /// # Safety
+    ///
+    /// The file must not be truncated, by this or any other process, until
+    /// hashing is done. Reading a mapped page past the new end of the file
+    /// raises SIGBUS.
+    #[cfg(feature = "mmap")]
+    pub unsafe fn allow_mmap(mut self) -> HashControl<'a> {
+        self.mmap = true;
+        self
+    }
+
+    pub(crate) fn default_total(&mut self, total: u64) {
+        self.total.get_or_insert(total);
+    }
+
+    pub(crate) fn check(&self) -> io::Result<()> {
+        match &self.cancel {
+            Some(token) if token.is_cancelled() => Err(io::Error::other(Cancelled)),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn advance(&mut self, bytes: usize) -> io::Result<()> {
+        let started = *self.started.get_or_insert_with(Instant::now);
+        self.bytes += bytes as u64;
+        if let Some(progress) = &mut self.progress {
+            progress(Progress {
+                bytes: self.bytes,
+                total: self.total,
+                elapsed: started.elapsed(),
+            });
+        }
+        self.check()
+    }
+
+    /// Feeds everything from `reader` to `update`, reporting progress and
+    /// stopping between reads once cancelled.
+    pub(crate) fn read_all<R, F>(&mut self, mut reader: R, mut update: F) -> io::Result<()>
+    where R: Read,
+          F: FnMut(&[u8]) {
+        self.started.get_or_insert_with(Instant::now);
+        self.check()?;
+        let mut buf = vec![0u8; BUFFER_SIZE];
+        loop {
+            let bytes_read = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            update(&buf[..bytes_read]);
+            self.advance(bytes_read)?;
+        }
+        self.advance(0)
+    }
+
+    /// Like `read_all`, but maps the file into memory instead if allowed with
+    /// `allow_mmap` and it is a regular, non-empty file.
+    pub(crate) fn read_file<F>(&mut self, file: File, update: F) -> io::Result<()>
+    where F: FnMut(&[u8]) {
+        #[cfg(feature = "mmap")]
+        let map = match self.mmap {
+            // SAFETY: the caller of `allow_mmap` guarantees the file is not
+            // truncated while it is hashed
+            true => unsafe { crate::hash::mmap::map_file(&file) },
+            false => None,
+        };
+        #[cfg(feature = "mmap")]
+        if let Some(map) = map {
+            let mut update = update;
+            self.started.get_or_insert_with(Instant::now);
+            self.check()?;
+            // still fed in buffer sized slices to keep reporting progress
+            for data in map.chunks(BUFFER_SIZE) {
+                update(data);
+                self.advance(data.len())?;
+            }
+            return self.advance(0);
+        }
+        self.read_all(file, update)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_progress() {
+        let data = vec![0u8; 2 * BUFFER_SIZE + 10];
+        let mut reports = Vec::new();
+        let mut seen = 0;
+        HashControl::new()
+            .total(data.len() as u64)
+            .on_progress(|p| reports.push(p))
+            .read_all(&data[..], |chunk| seen += chunk.len())
+            .unwrap();
+        assert_eq!(seen, data.len());
+        let last = reports.last().unwrap();
+        assert_eq!(last.bytes, data.len() as u64);
+        assert_eq!(last.fraction(), Some(1.0));
+        assert!(reports.windows(2).all(|w| w[0].bytes <= w[1].bytes));
+    }
+
+    #[test]
+    fn cancels_between_reads() {
+        let data = vec![0u8; 4 * BUFFER_SIZE];
+        let token = CancellationToken::new();
+        let mut seen = 0;
+        let err = HashControl::new()
+            .cancel_with(token.clone())
+            .on_progress(|_| token.cancel())
+            .read_all(&data[..], |chunk| seen += chunk.len())
+            .unwrap_err();
+        assert!(is_cancelled(&err));
+        assert_eq!(seen, BUFFER_SIZE);
+    }
+}
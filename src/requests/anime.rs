@@ -1,7 +1,19 @@
 use serde::{Serialize, Serializer};
 use serde::ser::SerializeMap;
+use anidb_derive::Mask;
 use super::AniDbRequest;
+use crate::cache::CacheKey;
 use crate::errors::AniDbError;
+use crate::mask::{decode_list, select_fields};
+use super::types::{
+    zip_relations,
+    AnimeType,
+    DateFlags,
+    PartialDate,
+    RelationType,
+    Timestamp,
+    YearRange,
+};
 
 pub enum AnimeRequest {
     Aid(u32, Option<AnimeRequestFields>),
@@ -29,27 +41,56 @@ impl AniDbRequest for AnimeRequest {
         reply: &str,
         data: &str
     ) -> Result<Self::Response, Self::Error> {
-        match &code[..] {
+        match code {
             "230" => {
-                data.split("\n").into_iter()
+                data.split("\n")
                     .filter(|line| !line.trim().is_empty())
                     .map(|line| {
                         self.decode_line(line)
                     }).collect()
             },
             "330" => Err(AnimeRequestError::NoSuchAnime),
-            code @ _ => Err(AniDbError::from((code, reply)).into())
+            code => Err(AniDbError::from((code, reply)).into())
         }
     }
+    fn cache_key(&self) -> Result<CacheKey, crate::ser::Error> {
+        Ok(CacheKey::new(Self::name(), &self.encode()?, &["amask"]))
+    }
+    fn reuse_cached(&self, stored: &CacheKey, code: &str, data: &str) -> Option<String> {
+        let fields = match self {
+            AnimeRequest::Aid(_, fields) | AnimeRequest::Aname(_, fields) => fields.as_ref()?,
+        };
+        let stored_fields = AnimeRequestFields::from_bytes(
+            &hex::decode(stored.mask("amask")?).ok()?
+        ).ok()?;
+        if !stored_fields.is_superset(fields) {
+            return None;
+        }
+        if code != "230" {
+            return Some(data.to_string());
+        }
+        data.split('\n')
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                let mut selected = Vec::new();
+                let wanted = |field| fields.contains(field);
+                select_fields(&mut line.split('|'), stored_fields.fields(), wanted, &mut selected)?;
+                Some(selected.join("|"))
+            })
+            .collect::<Option<Vec<_>>>()
+            .map(|lines| lines.join("\n"))
+    }
 }
 
 impl AnimeRequest {
     fn decode_line(&self, line: &str) -> Result<AnimeResponse, AnimeRequestError> {
-        let resp = match self {
+        let mut resp = match self {
             AnimeRequest::Aid(_, Some(fields)) | AnimeRequest::Aname(_, Some(fields)) => {
                 // 15456|0|2021-2021|TV Series|14767|2|Tensei Shitara Slime Datta Ken (2021 Dai 2 Bu)|転生したらスライムだった件 (2021 第2部)|That Time I Got Reincarnated as a Slime (2021 Part 2)|転生したらスライムだった件 (2021 第2部)'That Time I Got Reincarnated as a Slime (2021 Part 2)|Tensura 2 Part 2'Tensura (2021 Part 2)||12|8|3|1625529600|1632182400|http://www.ten-sura.com/|261282.jpg|743|76|743|79|0|0||0|24212|372060||themes,original work,novel|2607,2609,2799|0,0,0|1627893775|96305,98294,98296,98297,98298,98299,99097,99348,99350,99971,99972,101836,101838,101839,101846,114732,114735,114737,114739,118370,118466,118467,118717,118869,118870,118871,119025,119026,96306,96309,96299,96301,96302,96303,96304,96307,97711,97712,97713,97715,99349,101861,114738,117011,117348|1|2|0|0|0
                 let mut field_iter = line.split("|");
-                fields.decode_response(&mut field_iter)?
+                let mut resp = fields.decode_response(&mut field_iter)?;
+                resp.relations = zip_relations(&resp.related_aid_list, &resp.related_aid_type)?;
+                resp
             },
             _ => {
                 let mut resp = AnimeResponse::default();
@@ -64,22 +105,39 @@ impl AnimeRequest {
                 decode_field!(field_iter, resp, temp_vote_count, i32);
                 decode_field!(field_iter, resp, average_view_rating, i32);
                 decode_field!(field_iter, resp, review_count, i32);
-                decode_field!(field_iter, resp, year, String);
-                decode_field!(field_iter, resp, ty, String);
+                decode_field!(field_iter, resp, year, YearRange);
+                decode_field!(field_iter, resp, ty, AnimeType);
                 decode_field!(field_iter, resp, romaji_name, String);
                 decode_field!(field_iter, resp, kanji_name, String);
                 decode_field!(field_iter, resp, english_name, String);
-                decode_field!(field_iter, resp, other_name, String);
-                decode_field!(field_iter, resp, short_name_list, String);
-                decode_field!(field_iter, resp, synonym_list, String);
+                decode_field!(field_iter, resp, other_name, Vec<String> => decode_list);
+                decode_field!(field_iter, resp, short_name_list, Vec<String> => decode_list);
+                decode_field!(field_iter, resp, synonym_list, Vec<String> => decode_list);
                 decode_field!(field_iter, resp, category_list, Vec<String>);
                 resp
             }
         };
+        // on every path, though only the fields mask can select the dates
+        resp.apply_dateflags();
         Ok(resp)
     }
 }
 
+impl AnimeResponse {
+    // AniDB sends full timestamps for air and end dates even when only the
+    // year or month is known, `dateflags` tells which parts to trust
+    fn apply_dateflags(&mut self) {
+        if let Some(flags) = self.dateflags {
+            if let Some(air_date) = self.air_date.as_mut() {
+                flags.apply_to_start(air_date);
+            }
+            if let Some(end_date) = self.end_date.as_mut() {
+                flags.apply_to_end(end_date);
+            }
+        }
+    }
+}
+
 impl Serialize for AnimeRequest {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where S: Serializer {
@@ -114,56 +172,294 @@ pub enum AnimeRequestError {
 }
 impl_into_anidberror!(AnimeRequestError);
 
-generate_mask!(
-    AnimeRequestFields,
-    AnimeResponse,
-    AnimeRequestError,
-    7, i32, aid,
-    6, i32, dateflags,
-    5, String, year,
-    4, String, ty,
-    3, String, related_aid_list,
-    2, String, related_aid_type
-        &
-    7, String, romaji_name,
-    6, String, kanji_name,
-    5, String, english_name,
-    4, String, other_name,
-    3, String, short_name_list,
-    2, String, synonym_list
-        &
-    7, i32, episodes,
-    6, i32, highest_episode_number,
-    5, i32, special_ep_count,
-    4, i32, air_date,
-    3, i32, end_date,
-    2, String, url,
-    1, String, picname
-        &
-    7, i32, rating,
-    6, i32, vote_count,
-    5, i32, temp_rating,
-    4, i32, temp_vote_count,
-    3, i32, average_view_rating,
-    2, i32, review_count,
-    1, String, award_list,
-    0, bool, is_18plus_restricted
-        &
-    6, i32, ann_id,
-    5, i32, allcinema_id,
-    4, String, animenfo_id,
-    3, Vec<String>, tag_name_list,
-    2, Vec<i32>, tag_id_list,
-    1, Vec<i32>, tag_weight_list,
-    0, i32, date_record_updated
-        &
-    7, Vec<i32>, character_id_list
-        &
-    7, i32, specials_count,
-    6, i32, credits_count,
-    5, i32, other_count,
-    4, i32, trailer_count,
-    3, i32, parody_count
-        ;
-    Vec<String>, category_list
-);
+#[derive(Clone, Default, Debug, PartialEq, Mask)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+#[mask(
+    name = "AnimeRequestFields",
+    fields = "AnimeRequestField",
+    error = "AnimeRequestError",
+    bytes = 7,
+    reserved = "1:1, 1:0, 2:1, 2:0, 3:0, 5:7, 6:6, 6:5, 6:4, 6:3, 6:2, 6:1, 6:0, 7:2, 7:1, 7:0"
+)]
+pub struct AnimeResponse {
+    #[mask(byte = 1, bit = 7)]
+    pub aid: Option<i32>,
+    #[mask(byte = 1, bit = 6)]
+    pub dateflags: Option<DateFlags>,
+    #[mask(byte = 1, bit = 5)]
+    pub year: Option<YearRange>,
+    #[mask(byte = 1, bit = 4)]
+    pub ty: Option<AnimeType>,
+    #[mask(byte = 1, bit = 3, decode_with = "decode_list")]
+    pub related_aid_list: Option<Vec<i32>>,
+    #[mask(byte = 1, bit = 2, decode_with = "decode_list")]
+    pub related_aid_type: Option<Vec<RelationType>>,
+    #[mask(byte = 2, bit = 7)]
+    pub romaji_name: Option<String>,
+    #[mask(byte = 2, bit = 6)]
+    pub kanji_name: Option<String>,
+    #[mask(byte = 2, bit = 5)]
+    pub english_name: Option<String>,
+    #[mask(byte = 2, bit = 4, decode_with = "decode_list")]
+    pub other_name: Option<Vec<String>>,
+    #[mask(byte = 2, bit = 3, decode_with = "decode_list")]
+    pub short_name_list: Option<Vec<String>>,
+    #[mask(byte = 2, bit = 2, decode_with = "decode_list")]
+    pub synonym_list: Option<Vec<String>>,
+    #[mask(byte = 3, bit = 7)]
+    pub episodes: Option<i32>,
+    #[mask(byte = 3, bit = 6)]
+    pub highest_episode_number: Option<i32>,
+    #[mask(byte = 3, bit = 5)]
+    pub special_ep_count: Option<i32>,
+    #[mask(byte = 3, bit = 4)]
+    pub air_date: Option<PartialDate>,
+    #[mask(byte = 3, bit = 3)]
+    pub end_date: Option<PartialDate>,
+    #[mask(byte = 3, bit = 2)]
+    pub url: Option<String>,
+    #[mask(byte = 3, bit = 1)]
+    pub picname: Option<String>,
+    #[mask(byte = 4, bit = 7)]
+    pub rating: Option<i32>,
+    #[mask(byte = 4, bit = 6)]
+    pub vote_count: Option<i32>,
+    #[mask(byte = 4, bit = 5)]
+    pub temp_rating: Option<i32>,
+    #[mask(byte = 4, bit = 4)]
+    pub temp_vote_count: Option<i32>,
+    #[mask(byte = 4, bit = 3)]
+    pub average_view_rating: Option<i32>,
+    #[mask(byte = 4, bit = 2)]
+    pub review_count: Option<i32>,
+    #[mask(byte = 4, bit = 1)]
+    pub award_list: Option<String>,
+    #[mask(byte = 4, bit = 0)]
+    pub is_18plus_restricted: Option<bool>,
+    #[mask(byte = 5, bit = 6)]
+    pub ann_id: Option<i32>,
+    #[mask(byte = 5, bit = 5)]
+    pub allcinema_id: Option<i32>,
+    #[mask(byte = 5, bit = 4)]
+    pub animenfo_id: Option<String>,
+    #[mask(byte = 5, bit = 3)]
+    pub tag_name_list: Option<Vec<String>>,
+    #[mask(byte = 5, bit = 2)]
+    pub tag_id_list: Option<Vec<i32>>,
+    #[mask(byte = 5, bit = 1)]
+    pub tag_weight_list: Option<Vec<i32>>,
+    #[mask(byte = 5, bit = 0)]
+    pub date_record_updated: Option<Timestamp>,
+    #[mask(byte = 6, bit = 7)]
+    pub character_id_list: Option<Vec<i32>>,
+    #[mask(byte = 7, bit = 7)]
+    pub specials_count: Option<i32>,
+    #[mask(byte = 7, bit = 6)]
+    pub credits_count: Option<i32>,
+    #[mask(byte = 7, bit = 5)]
+    pub other_count: Option<i32>,
+    #[mask(byte = 7, bit = 4)]
+    pub trailer_count: Option<i32>,
+    #[mask(byte = 7, bit = 3)]
+    pub parody_count: Option<i32>,
+    // not in the ANIME mask: the category list only comes with the reply to a
+    // request without one, relations are zipped from the lists above
+    pub category_list: Option<Vec<String>>,
+    pub relations: Option<Vec<(i32, RelationType)>>,
+}
+
+impl AnimeRequestFields {
+    pub fn titles() -> AnimeRequestFields {
+        AnimeRequestFields::from_fields([
+            AnimeRequestField::RomajiName,
+            AnimeRequestField::KanjiName,
+            AnimeRequestField::EnglishName,
+            AnimeRequestField::OtherName,
+            AnimeRequestField::ShortNameList,
+            AnimeRequestField::SynonymList,
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type AnimeCheck = fn(&AnimeResponse) -> bool;
+
+    // every documented amask bit with a field: byte, bit, value sent by
+    // AniDB and how it should decode
+    const AMASK: &[(usize, u8, &str, AnimeCheck)] = &[
+        (1, 7, "15456", |r| r.aid == Some(15456)),
+        (1, 6, "9", |r| r.dateflags == Some(DateFlags(9))),
+        (1, 5, "2021-?", |r| r.year == Some(YearRange { start: Some(2021), end: None })),
+        (1, 4, "Movie", |r| r.ty == Some(AnimeType::Movie)),
+        (1, 3, "2'3", |r| r.related_aid_list == Some(vec![2, 3])),
+        (1, 2, "1'2", |r| r.related_aid_type == Some(vec![RelationType::Sequel, RelationType::Prequel])),
+        (2, 7, "romaji", |r| r.romaji_name.as_deref() == Some("romaji")),
+        (2, 6, "kanji", |r| r.kanji_name.as_deref() == Some("kanji")),
+        (2, 5, "english", |r| r.english_name.as_deref() == Some("english")),
+        (2, 4, "a'b", |r| r.other_name == Some(vec![String::from("a"), String::from("b")])),
+        (2, 3, "s", |r| r.short_name_list == Some(vec![String::from("s")])),
+        (2, 2, "x`s", |r| r.synonym_list == Some(vec![String::from("x's")])),
+        (3, 7, "12", |r| r.episodes == Some(12)),
+        (3, 6, "13", |r| r.highest_episode_number == Some(13)),
+        (3, 5, "3", |r| r.special_ep_count == Some(3)),
+        (3, 4, "1625529600", |r| r.air_date.map(|d| d.to_string()).as_deref() == Some("2021-07-06")),
+        (3, 3, "0", |r| r.end_date == Some(PartialDate::default())),
+        (3, 2, "http://example.org/", |r| r.url.as_deref() == Some("http://example.org/")),
+        (3, 1, "1.jpg", |r| r.picname.as_deref() == Some("1.jpg")),
+        (4, 7, "743", |r| r.rating == Some(743)),
+        (4, 6, "76", |r| r.vote_count == Some(76)),
+        (4, 5, "744", |r| r.temp_rating == Some(744)),
+        (4, 4, "79", |r| r.temp_vote_count == Some(79)),
+        (4, 3, "800", |r| r.average_view_rating == Some(800)),
+        (4, 2, "4", |r| r.review_count == Some(4)),
+        (4, 1, "award", |r| r.award_list.as_deref() == Some("award")),
+        (4, 0, "1", |r| r.is_18plus_restricted == Some(true)),
+        (5, 6, "24212", |r| r.ann_id == Some(24212)),
+        (5, 5, "372060", |r| r.allcinema_id == Some(372060)),
+        (5, 4, "nfo", |r| r.animenfo_id.as_deref() == Some("nfo")),
+        (5, 3, "themes,novel", |r| r.tag_name_list == Some(vec![String::from("themes"), String::from("novel")])),
+        (5, 2, "2607,2609", |r| r.tag_id_list == Some(vec![2607, 2609])),
+        (5, 1, "0,600", |r| r.tag_weight_list == Some(vec![0, 600])),
+        (5, 0, "1627893775", |r| r.date_record_updated == Some(Timestamp(1627893775))),
+        (6, 7, "96305,98294", |r| r.character_id_list == Some(vec![96305, 98294])),
+        (7, 7, "1", |r| r.specials_count == Some(1)),
+        (7, 6, "2", |r| r.credits_count == Some(2)),
+        (7, 5, "3", |r| r.other_count == Some(3)),
+        (7, 4, "4", |r| r.trailer_count == Some(4)),
+        (7, 3, "5", |r| r.parody_count == Some(5)),
+    ];
+
+    // lists AniDB leaves empty for anime without tags or characters
+    const AMASK_EMPTY: &[(usize, u8, &str, AnimeCheck)] = &[
+        (5, 3, "", |r| r.tag_name_list == Some(Vec::new())),
+        (5, 2, "", |r| r.tag_id_list == Some(Vec::new())),
+        (5, 1, "", |r| r.tag_weight_list == Some(Vec::new())),
+        (6, 7, "", |r| r.character_id_list == Some(Vec::new())),
+    ];
+
+    const AMASK_RESERVED: &[(usize, u8)] = &[
+        (1, 1), (1, 0), (2, 1), (2, 0), (3, 0), (5, 7),
+        (6, 6), (6, 5), (6, 4), (6, 3), (6, 2), (6, 1), (6, 0),
+        (7, 2), (7, 1), (7, 0),
+    ];
+
+    fn single_bit(byte: usize, bit: u8) -> Vec<u8> {
+        let mut bytes = vec![0u8; 7];
+        bytes[byte - 1] = 1 << bit;
+        bytes
+    }
+
+    #[test]
+    fn every_amask_bit_decodes_its_field() {
+        for &(byte, bit, value, check) in AMASK.iter().chain(AMASK_EMPTY) {
+            let bytes = single_bit(byte, bit);
+            let fields = AnimeRequestFields::from_bytes(&bytes).unwrap();
+            assert_eq!(fields.to_bytes().to_vec(), bytes, "byte {} bit {}", byte, bit);
+            let request = AnimeRequest::from_anime_id(1, Some(fields.clone()));
+            assert_eq!(
+                request.encode().unwrap(),
+                format!("aid=1&amask={}", hex::encode(&bytes))
+            );
+            let mut field_iter = vec![value, "next"].into_iter();
+            let resp = fields.decode_response(&mut field_iter).unwrap();
+            assert!(check(&resp), "byte {} bit {}: {:?}", byte, bit, resp);
+            assert_eq!(field_iter.next(), Some("next"), "byte {} bit {}", byte, bit);
+        }
+    }
+
+    #[test]
+    fn reserved_bits_are_never_requested() {
+        for &(byte, bit) in AMASK_RESERVED {
+            let fields = AnimeRequestFields::from_bytes(&single_bit(byte, bit)).unwrap();
+            assert_eq!(fields.to_bytes(), [0u8; 7], "byte {} bit {}", byte, bit);
+        }
+        assert_eq!(
+            AnimeRequestFields::from_bytes(&[0xff; 7]).unwrap().to_bytes(),
+            AnimeRequestFields::all().to_bytes()
+        );
+        assert!(AnimeRequestFields::from_bytes(&[0xff; 6]).is_err());
+    }
+
+    #[test]
+    fn all_bits_decode_in_order() {
+        let values: Vec<&str> = AMASK.iter().map(|row| row.2).collect();
+        let request = AnimeRequest::from_anime_id(1, Some(AnimeRequestFields::all()));
+        let resp = request.decode_response("230", "ANIME", &values.join("|")).unwrap();
+        assert_eq!(resp.len(), 1);
+        for &(byte, bit, _, check) in AMASK {
+            // dateflags 9 drops the unknown start day once applied
+            if (byte, bit) == (3, 4) {
+                assert_eq!(resp[0].air_date.unwrap().to_string(), "2021-07");
+                continue;
+            }
+            assert!(check(&resp[0]), "byte {} bit {}", byte, bit);
+        }
+        assert_eq!(
+            resp[0].relations,
+            Some(vec![(2, RelationType::Sequel), (3, RelationType::Prequel)])
+        );
+    }
+
+    #[test]
+    fn reuses_superset_replies() {
+        let wide = AnimeRequest::from_anime_name("slime", Some(AnimeRequestFields::from_fields([
+            AnimeRequestField::Aid,
+            AnimeRequestField::RomajiName,
+            AnimeRequestField::Episodes,
+        ])));
+        let stored = wide.cache_key().unwrap();
+        let data = "15456|Tensura 2 Part 2|12\n14767|Tensura 2|12\n";
+
+        let fields = AnimeRequestFields::none()
+            .with(AnimeRequestField::Aid)
+            .with(AnimeRequestField::Episodes);
+        let narrow = AnimeRequest::from_anime_name("slime", Some(fields));
+        let reused = narrow.reuse_cached(&stored, "230", data).unwrap();
+        assert_eq!(reused, "15456|12\n14767|12");
+        let resp = narrow.decode_response("230", "ANIME", &reused).unwrap();
+        assert_eq!(
+            resp.iter().map(|r| (r.aid, r.episodes, r.romaji_name.clone())).collect::<Vec<_>>(),
+            vec![(Some(15456), Some(12), None), (Some(14767), Some(12), None)]
+        );
+        assert_eq!(narrow.reuse_cached(&stored, "330", "").as_deref(), Some(""));
+
+        let wider = AnimeRequest::from_anime_name("slime", Some(AnimeRequestFields::all()));
+        assert_eq!(wider.reuse_cached(&stored, "230", data), None);
+        assert_eq!(AnimeRequest::from_anime_name("slime", None).reuse_cached(&stored, "230", data), None);
+        // the second line has too few values for the stored mask
+        assert_eq!(narrow.reuse_cached(&stored, "230", "15456|Tensura 2 Part 2|12\n14767"), None);
+    }
+
+    #[test]
+    fn applies_dateflags() {
+        let fields = AnimeRequestFields::none()
+            .with(AnimeRequestField::Dateflags)
+            .with(AnimeRequestField::AirDate)
+            .with(AnimeRequestField::EndDate);
+        let request = AnimeRequest::from_anime_id(1, Some(fields));
+        // start day and end month unknown
+        let resp = request.decode_response("230", "ANIME", "9|1625529600|1632182400\n").unwrap();
+        assert_eq!(resp[0].air_date.unwrap().to_string(), "2021-07");
+        assert_eq!(resp[0].end_date.unwrap().to_string(), "2021");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let request = AnimeRequest::from_anime_id(1, Some(AnimeRequestFields::all()));
+        let mut values = vec!["0"; AnimeRequestField::ALL.len()];
+        values[0] = "15456";
+        values[2] = "2021-2021";
+        values[3] = "TV Series";
+        values[4] = "14767'1";
+        values[5] = "2'1";
+        values[6] = "Tensei Shitara Slime Datta Ken";
+        values[9] = "Tensura'That Time";
+        let resp = request.decode_response("230", "ANIME", &values.join("|")).unwrap();
+        let json = serde_json::to_string(&resp).unwrap();
+        assert_eq!(serde_json::from_str::<Vec<AnimeResponse>>(&json).unwrap(), resp);
+    }
+}
@@ -19,19 +19,14 @@ where
     }).serialize(s)
 }
 
-#[derive(Clone, Serialize)]
+#[derive(Clone, Default, Serialize)]
 // One of https://docs.oracle.com/javase/1.5.0/docs/guide/intl/encoding.doc.html
 pub enum Encoding {
     #[serde(rename="UTF8")]
+    #[default]
     Utf8
 }
 
-impl Default for Encoding {
-    fn default() -> Encoding {
-        Encoding::Utf8
-    }
-}
-
 #[skip_serializing_none]
 #[derive(Clone, Serialize, TypedBuilder)]
 pub struct AuthRequest {
@@ -58,7 +53,8 @@ pub struct AuthRequest {
     imgserver: Option<bool>,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
 pub struct AuthResponse {
     pub session_id: String,
     pub new_version_available: bool,
@@ -83,7 +79,7 @@ impl AniDbRequest for AuthRequest {
         reply: &str,
         _data: &str
     ) -> Result<Self::Response, Self::Error> {
-        match &code[..] {
+        match code {
             code @ ("200" | "201") => {
                 let mut reply_iter = reply.split(" ");
                 let session_str = reply_iter.next()
@@ -121,12 +117,12 @@ impl AniDbRequest for AuthRequest {
                             nat: ip_addr
                         }
                     ),
-                    code @ _ => {
-                        Err(AniDbError::from((code, reply)).into())
+                    code => {
+                        Err(AniDbError::from((code, reply)))
                     }
                 }
             },
-            code @ _ => Err(AniDbError::from((code, reply)).into())
+            code => Err(AniDbError::from((code, reply)))
         }
     }
 }
@@ -1,85 +1,220 @@
 use std::num::ParseIntError;
 use serde::{Serialize, Serializer};
 use serde::ser::SerializeMap;
+use anidb_derive::Mask;
 use super::AniDbRequest;
+use crate::cache::CacheKey;
 use crate::errors::AniDbError;
-use super::types::EpNo;
-
-generate_mask!(
-    FileMask,
-    FileMaskResponse,
-    FileRequestError,
-    6, i32, aid,
-    5, i32, eid,
-    4, i32, gid,
-    3, i32, mylist_id,
-    2, String, other_episodes,
-    1, i16, is_deprecated,
-    0, i16, state
-        &
-    7, i64, size,
-    6, String, ed2k,
-    5, String, md5,
-    4, String, sha1,
-    3, String, crc32,
-    1, i32, video_colour_depth
-        &
-    7, String, quality,
-    6, String, source,
-    5, String, audio_codec_list,
-    4, i32, audio_bitrate_list,
-    3, String, video_codec,
-    2, i32, video_bitrate,
-    1, String, video_resolution,
-    0, String, file_type,
-    7, String, dub_language,
-    6, String, sub_language,
-    5, i32, length_in_seconds,
-    4, String, description,
-    3, i32, aired_date,
-    0, String, anidb_file_name
-        &
-    7, i32, mylist_state,
-    6, i32, mylist_filestate,
-    5, i32, mylist_viewed,
-    4, i32, mylist_viewdate,
-    3, String, mylist_storage,
-    2, String, mylist_source,
-    1, String, mylist_other
-        ;
-);
-
-generate_mask!(
-    AnimeMask,
-    AnimeMaskResponse,
-    FileRequestError,
-    7, i32, anime_total_episodes,
-    6, i32, highest_episode_number,
-    5, String, year,
-    4, String, ty,
-    3, String, related_aid_list,
-    2, String, related_aid_type,
-    1, String, category_list
-        &
-    7, String, romaji_name,
-    6, String, kanji_name,
-    5, String, english_name,
-    4, String, other_name,
-    3, String, short_name_list,
-    2, String, synonym_list
-        &
-    7, EpNo, epno,
-    6, String, ep_name,
-    5, String, ep_romaji_name,
-    4, String, ep_kanji_name,
-    3, i32, episode_rating,
-    2, i32, episode_vote_count
-        &
-    7, String, group_name,
-    6, String, group_short_name,
-    0, i32, date_aid_record_updated
-        ;
-);
+use crate::mask::{decode_list, select_fields};
+use super::types::{
+    zip_relations,
+    AnimeType,
+    EpNoRange,
+    FileState,
+    RelationType,
+    Timestamp,
+    YearRange,
+};
+
+#[derive(Clone, Default, Debug, PartialEq, Mask)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+#[mask(
+    name = "FileMask",
+    error = "FileRequestError",
+    bytes = 5,
+    reserved = "1:7, 2:2, 2:0, 4:2, 4:1, 5:0"
+)]
+pub struct FileMaskResponse {
+    #[mask(byte = 1, bit = 6)]
+    pub aid: Option<i32>,
+    #[mask(byte = 1, bit = 5)]
+    pub eid: Option<i32>,
+    #[mask(byte = 1, bit = 4)]
+    pub gid: Option<i32>,
+    #[mask(byte = 1, bit = 3)]
+    pub mylist_id: Option<i32>,
+    #[mask(byte = 1, bit = 2, decode_with = "decode_list")]
+    pub other_episodes: Option<Vec<String>>,
+    #[mask(byte = 1, bit = 1)]
+    pub is_deprecated: Option<i16>,
+    #[mask(byte = 1, bit = 0)]
+    pub state: Option<FileState>,
+    #[mask(byte = 2, bit = 7)]
+    pub size: Option<i64>,
+    #[mask(byte = 2, bit = 6)]
+    pub ed2k: Option<String>,
+    #[mask(byte = 2, bit = 5)]
+    pub md5: Option<String>,
+    #[mask(byte = 2, bit = 4)]
+    pub sha1: Option<String>,
+    #[mask(byte = 2, bit = 3)]
+    pub crc32: Option<String>,
+    #[mask(byte = 2, bit = 1)]
+    pub video_colour_depth: Option<i32>,
+    #[mask(byte = 3, bit = 7)]
+    pub quality: Option<String>,
+    #[mask(byte = 3, bit = 6)]
+    pub source: Option<String>,
+    #[mask(byte = 3, bit = 5, decode_with = "decode_list")]
+    pub audio_codec_list: Option<Vec<String>>,
+    #[mask(byte = 3, bit = 4, decode_with = "decode_list")]
+    pub audio_bitrate_list: Option<Vec<i32>>,
+    #[mask(byte = 3, bit = 3)]
+    pub video_codec: Option<String>,
+    #[mask(byte = 3, bit = 2)]
+    pub video_bitrate: Option<i32>,
+    #[mask(byte = 3, bit = 1)]
+    pub video_resolution: Option<String>,
+    #[mask(byte = 3, bit = 0)]
+    pub file_type: Option<String>,
+    #[mask(byte = 4, bit = 7, decode_with = "decode_list")]
+    pub dub_language: Option<Vec<String>>,
+    #[mask(byte = 4, bit = 6, decode_with = "decode_list")]
+    pub sub_language: Option<Vec<String>>,
+    #[mask(byte = 4, bit = 5)]
+    pub length_in_seconds: Option<i32>,
+    #[mask(byte = 4, bit = 4)]
+    pub description: Option<String>,
+    #[mask(byte = 4, bit = 3)]
+    pub aired_date: Option<Timestamp>,
+    #[mask(byte = 4, bit = 0)]
+    pub anidb_file_name: Option<String>,
+    #[mask(byte = 5, bit = 7)]
+    pub mylist_state: Option<i32>,
+    #[mask(byte = 5, bit = 6)]
+    pub mylist_filestate: Option<i32>,
+    #[mask(byte = 5, bit = 5)]
+    pub mylist_viewed: Option<i32>,
+    #[mask(byte = 5, bit = 4)]
+    pub mylist_viewdate: Option<Timestamp>,
+    #[mask(byte = 5, bit = 3)]
+    pub mylist_storage: Option<String>,
+    #[mask(byte = 5, bit = 2)]
+    pub mylist_source: Option<String>,
+    #[mask(byte = 5, bit = 1)]
+    pub mylist_other: Option<String>,
+}
+
+#[derive(Clone, Default, Debug, PartialEq, Mask)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+#[mask(
+    name = "AnimeMask",
+    error = "FileRequestError",
+    bytes = 4,
+    reserved = "1:0, 2:1, 2:0, 3:1, 3:0, 4:5, 4:4, 4:3, 4:2, 4:1"
+)]
+pub struct AnimeMaskResponse {
+    #[mask(byte = 1, bit = 7)]
+    pub anime_total_episodes: Option<i32>,
+    #[mask(byte = 1, bit = 6)]
+    pub highest_episode_number: Option<i32>,
+    #[mask(byte = 1, bit = 5)]
+    pub year: Option<YearRange>,
+    #[mask(byte = 1, bit = 4)]
+    pub ty: Option<AnimeType>,
+    #[mask(byte = 1, bit = 3, decode_with = "decode_list")]
+    pub related_aid_list: Option<Vec<i32>>,
+    #[mask(byte = 1, bit = 2, decode_with = "decode_list")]
+    pub related_aid_type: Option<Vec<RelationType>>,
+    #[mask(byte = 1, bit = 1, decode_with = "decode_list")]
+    pub category_list: Option<Vec<String>>,
+    #[mask(byte = 2, bit = 7)]
+    pub romaji_name: Option<String>,
+    #[mask(byte = 2, bit = 6)]
+    pub kanji_name: Option<String>,
+    #[mask(byte = 2, bit = 5)]
+    pub english_name: Option<String>,
+    #[mask(byte = 2, bit = 4, decode_with = "decode_list")]
+    pub other_name: Option<Vec<String>>,
+    #[mask(byte = 2, bit = 3, decode_with = "decode_list")]
+    pub short_name_list: Option<Vec<String>>,
+    #[mask(byte = 2, bit = 2, decode_with = "decode_list")]
+    pub synonym_list: Option<Vec<String>>,
+    #[mask(byte = 3, bit = 7)]
+    pub epno: Option<EpNoRange>,
+    #[mask(byte = 3, bit = 6)]
+    pub ep_name: Option<String>,
+    #[mask(byte = 3, bit = 5)]
+    pub ep_romaji_name: Option<String>,
+    #[mask(byte = 3, bit = 4)]
+    pub ep_kanji_name: Option<String>,
+    #[mask(byte = 3, bit = 3)]
+    pub episode_rating: Option<i32>,
+    #[mask(byte = 3, bit = 2)]
+    pub episode_vote_count: Option<i32>,
+    #[mask(byte = 4, bit = 7)]
+    pub group_name: Option<String>,
+    #[mask(byte = 4, bit = 6)]
+    pub group_short_name: Option<String>,
+    #[mask(byte = 4, bit = 0)]
+    pub date_aid_record_updated: Option<Timestamp>,
+    // not part of the mask, filled in after decoding
+    pub relations: Option<Vec<(i32, RelationType)>>,
+}
+
+impl FileMask {
+    pub fn hashes() -> FileMask {
+        FileMask::from_fields([
+            FileMaskField::Size,
+            FileMaskField::Ed2k,
+            FileMaskField::Md5,
+            FileMaskField::Sha1,
+            FileMaskField::Crc32,
+        ])
+    }
+
+    pub fn media_info() -> FileMask {
+        FileMask::from_fields([
+            FileMaskField::VideoColourDepth,
+            FileMaskField::Quality,
+            FileMaskField::Source,
+            FileMaskField::AudioCodecList,
+            FileMaskField::AudioBitrateList,
+            FileMaskField::VideoCodec,
+            FileMaskField::VideoBitrate,
+            FileMaskField::VideoResolution,
+            FileMaskField::FileType,
+            FileMaskField::DubLanguage,
+            FileMaskField::SubLanguage,
+            FileMaskField::LengthInSeconds,
+        ])
+    }
+
+    pub fn mylist() -> FileMask {
+        FileMask::from_fields([
+            FileMaskField::MylistId,
+            FileMaskField::MylistState,
+            FileMaskField::MylistFilestate,
+            FileMaskField::MylistViewed,
+            FileMaskField::MylistViewdate,
+            FileMaskField::MylistStorage,
+            FileMaskField::MylistSource,
+            FileMaskField::MylistOther,
+        ])
+    }
+}
+
+impl AnimeMask {
+    pub fn titles() -> AnimeMask {
+        AnimeMask::from_fields([
+            AnimeMaskField::RomajiName,
+            AnimeMaskField::KanjiName,
+            AnimeMaskField::EnglishName,
+            AnimeMaskField::OtherName,
+            AnimeMaskField::ShortNameList,
+            AnimeMaskField::SynonymList,
+        ])
+    }
+
+    pub fn episode_titles() -> AnimeMask {
+        AnimeMask::from_fields([
+            AnimeMaskField::Epno,
+            AnimeMaskField::EpName,
+            AnimeMaskField::EpRomajiName,
+            AnimeMaskField::EpKanjiName,
+        ])
+    }
+}
 
 pub enum FileRequest {
     Fid(i32, Option<FileMask>, Option<AnimeMask>),
@@ -136,6 +271,9 @@ pub enum FileRequestError {
 }
 impl_into_anidberror!(FileRequestError);
 
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
 pub enum FileResponse {
     File(i32, Option<FileMaskResponse>, Option<AnimeMaskResponse>),
     MultipleFiles(Vec<i32>),
@@ -153,13 +291,13 @@ impl AniDbRequest for FileRequest {
         reply: &str,
         data: &str
     ) -> Result<Self::Response, Self::Error> {
-        match &code[..] {
+        match code {
             "220" => {
                 match &self {
                     &FileRequest::Fid(_, fmask, amask) | &FileRequest::SizeEd2k(_, _, fmask, amask) => {
                         let mut field_iter = data.trim().split("|");
                         let fid = next_or_decode_error!(field_iter)?.parse()?;
-                        let (fresp, aresp) = match (fmask, amask) {
+                        let (fresp, mut aresp) = match (fmask, amask) {
                             (Some(fmask), Some(amask)) => {
                                 (
                                     Some(fmask.decode_response(&mut field_iter)?),
@@ -183,7 +321,7 @@ impl AniDbRequest for FileRequest {
                                 let mut resp = FileMaskResponse::default();
                                 decode_field!(field_iter, resp, aid, i32);
                                 decode_field!(field_iter, resp, gid, i32);
-                                decode_field!(field_iter, resp, state, i16);
+                                decode_field!(field_iter, resp, state, FileState);
                                 decode_field!(field_iter, resp, size, i64);
                                 decode_field!(field_iter, resp, ed2k, String);
                                 decode_field!(field_iter, resp, anidb_file_name, String);
@@ -193,6 +331,9 @@ impl AniDbRequest for FileRequest {
                                 )
                             }
                         };
+                        if let Some(aresp) = aresp.as_mut() {
+                            aresp.relations = zip_relations(&aresp.related_aid_list, &aresp.related_aid_type)?;
+                        }
                         Ok(FileResponse::File(fid, fresp, aresp))
                     }
                 }
@@ -205,7 +346,295 @@ impl AniDbRequest for FileRequest {
                 ))
             }
             "320" => Err(FileRequestError::NoSuchFile),
-            code @ _ => Err(AniDbError::from((code, reply)).into())
+            code => Err(AniDbError::from((code, reply)).into())
+        }
+    }
+    fn cache_key(&self) -> Result<CacheKey, crate::ser::Error> {
+        Ok(CacheKey::new(Self::name(), &self.encode()?, &["fmask", "amask"]))
+    }
+    fn reuse_cached(&self, stored: &CacheKey, code: &str, data: &str) -> Option<String> {
+        let (fmask, amask) = match self {
+            FileRequest::Fid(_, fmask, amask) | FileRequest::SizeEd2k(_, _, fmask, amask) => {
+                (fmask, amask)
+            }
+        };
+        // without masks AniDB replies with a fixed set of fields instead
+        if fmask.is_none() && amask.is_none() {
+            return None;
+        }
+        let fmask = fmask.clone().unwrap_or_else(FileMask::none);
+        let amask = amask.clone().unwrap_or_else(AnimeMask::none);
+        let stored_fmask = FileMask::from_bytes(&hex::decode(stored.mask("fmask")?).ok()?).ok()?;
+        let stored_amask = AnimeMask::from_bytes(&hex::decode(stored.mask("amask")?).ok()?).ok()?;
+        if !stored_fmask.is_superset(&fmask) || !stored_amask.is_superset(&amask) {
+            return None;
+        }
+        // only a found file's fields depend on the masks
+        if code != "220" {
+            return Some(data.to_string());
+        }
+        let mut values = data.trim().split('|');
+        let mut selected = vec![values.next()?];
+        select_fields(&mut values, stored_fmask.fields(), |f| fmask.contains(f), &mut selected)?;
+        select_fields(&mut values, stored_amask.fields(), |f| amask.contains(f), &mut selected)?;
+        Some(selected.join("|"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FieldValue;
+    use crate::requests::types::EpNo;
+
+    type FileCheck = fn(&FileMaskResponse) -> bool;
+    type AnimeCheck = fn(&AnimeMaskResponse) -> bool;
+
+    // every documented fmask bit with a field: byte, bit, value sent by
+    // AniDB and how it should decode
+    const FMASK: &[(usize, u8, &str, FileCheck)] = &[
+        (1, 6, "1", |r| r.aid == Some(1)),
+        (1, 5, "2", |r| r.eid == Some(2)),
+        (1, 4, "3", |r| r.gid == Some(3)),
+        (1, 3, "4", |r| r.mylist_id == Some(4)),
+        (1, 2, "5,100'6,50", |r| r.other_episodes == Some(vec![String::from("5,100"), String::from("6,50")])),
+        (1, 1, "0", |r| r.is_deprecated == Some(0)),
+        (1, 0, "9", |r| r.state == Some(FileState(9))),
+        (2, 7, "1024", |r| r.size == Some(1024)),
+        (2, 6, "ed2k", |r| r.ed2k.as_deref() == Some("ed2k")),
+        (2, 5, "md5", |r| r.md5.as_deref() == Some("md5")),
+        (2, 4, "sha1", |r| r.sha1.as_deref() == Some("sha1")),
+        (2, 3, "crc32", |r| r.crc32.as_deref() == Some("crc32")),
+        (2, 1, "10", |r| r.video_colour_depth == Some(10)),
+        (3, 7, "high", |r| r.quality.as_deref() == Some("high")),
+        (3, 6, "www", |r| r.source.as_deref() == Some("www")),
+        (3, 5, "aac'opus", |r| r.audio_codec_list == Some(vec![String::from("aac"), String::from("opus")])),
+        (3, 4, "192'128", |r| r.audio_bitrate_list == Some(vec![192, 128])),
+        (3, 3, "H264/AVC", |r| r.video_codec.as_deref() == Some("H264/AVC")),
+        (3, 2, "1500", |r| r.video_bitrate == Some(1500)),
+        (3, 1, "1920x1080", |r| r.video_resolution.as_deref() == Some("1920x1080")),
+        (3, 0, "mkv", |r| r.file_type.as_deref() == Some("mkv")),
+        (4, 7, "japanese'english", |r| r.dub_language == Some(vec![String::from("japanese"), String::from("english")])),
+        (4, 6, "english", |r| r.sub_language == Some(vec![String::from("english")])),
+        (4, 5, "1440", |r| r.length_in_seconds == Some(1440)),
+        (4, 4, "a<br />b", |r| r.description.as_deref() == Some("a\nb")),
+        (4, 3, "1625529600", |r| r.aired_date == Some(Timestamp(1625529600))),
+        (4, 0, "file.mkv", |r| r.anidb_file_name.as_deref() == Some("file.mkv")),
+        (5, 7, "1", |r| r.mylist_state == Some(1)),
+        (5, 6, "2", |r| r.mylist_filestate == Some(2)),
+        (5, 5, "1", |r| r.mylist_viewed == Some(1)),
+        (5, 4, "1625529600", |r| r.mylist_viewdate == Some(Timestamp(1625529600))),
+        (5, 3, "hdd", |r| r.mylist_storage.as_deref() == Some("hdd")),
+        (5, 2, "web", |r| r.mylist_source.as_deref() == Some("web")),
+        (5, 1, "note", |r| r.mylist_other.as_deref() == Some("note")),
+    ];
+
+    const FMASK_RESERVED: &[(usize, u8)] = &[(1, 7), (2, 2), (2, 0), (4, 2), (4, 1), (5, 0)];
+
+    const AMASK: &[(usize, u8, &str, AnimeCheck)] = &[
+        (1, 7, "12", |r| r.anime_total_episodes == Some(12)),
+        (1, 6, "13", |r| r.highest_episode_number == Some(13)),
+        (1, 5, "2021-2022", |r| r.year == Some(YearRange { start: Some(2021), end: Some(2022) })),
+        (1, 4, "TV Series", |r| r.ty == Some(AnimeType::TvSeries)),
+        (1, 3, "2'3", |r| r.related_aid_list == Some(vec![2, 3])),
+        (1, 2, "1'2", |r| r.related_aid_type == Some(vec![RelationType::Sequel, RelationType::Prequel])),
+        (1, 1, "action'comedy", |r| r.category_list == Some(vec![String::from("action"), String::from("comedy")])),
+        (2, 7, "romaji", |r| r.romaji_name.as_deref() == Some("romaji")),
+        (2, 6, "kanji", |r| r.kanji_name.as_deref() == Some("kanji")),
+        (2, 5, "english", |r| r.english_name.as_deref() == Some("english")),
+        (2, 4, "a'b", |r| r.other_name == Some(vec![String::from("a"), String::from("b")])),
+        (2, 3, "s", |r| r.short_name_list == Some(vec![String::from("s")])),
+        (2, 2, "x`s", |r| r.synonym_list == Some(vec![String::from("x's")])),
+        (3, 7, "S1", |r| r.epno == Some("S1".parse().unwrap())),
+        (3, 6, "name", |r| r.ep_name.as_deref() == Some("name")),
+        (3, 5, "romaji", |r| r.ep_romaji_name.as_deref() == Some("romaji")),
+        (3, 4, "kanji", |r| r.ep_kanji_name.as_deref() == Some("kanji")),
+        (3, 3, "800", |r| r.episode_rating == Some(800)),
+        (3, 2, "5", |r| r.episode_vote_count == Some(5)),
+        (4, 7, "group", |r| r.group_name.as_deref() == Some("group")),
+        (4, 6, "grp", |r| r.group_short_name.as_deref() == Some("grp")),
+        (4, 0, "1625529600", |r| r.date_aid_record_updated == Some(Timestamp(1625529600))),
+    ];
+
+    const AMASK_RESERVED: &[(usize, u8)] = &[
+        (1, 0), (2, 1), (2, 0), (3, 1), (3, 0), (4, 5), (4, 4), (4, 3), (4, 2), (4, 1)
+    ];
+
+    fn single_bit(len: usize, byte: usize, bit: u8) -> Vec<u8> {
+        let mut bytes = vec![0u8; len];
+        bytes[byte - 1] = 1 << bit;
+        bytes
+    }
+
+    fn decode(
+        request: &FileRequest,
+        data: &str
+    ) -> (Option<FileMaskResponse>, Option<AnimeMaskResponse>) {
+        match request.decode_response("220", "FILE", data).unwrap() {
+            FileResponse::File(_, fresp, aresp) => (fresp, aresp),
+            FileResponse::MultipleFiles(_) => panic!("expected a single file"),
         }
     }
+
+    #[test]
+    fn every_fmask_bit_decodes_its_field() {
+        for &(byte, bit, value, check) in FMASK {
+            let bytes = single_bit(5, byte, bit);
+            let mask = FileMask::from_bytes(&bytes).unwrap();
+            assert_eq!(mask.to_bytes().to_vec(), bytes, "byte {} bit {}", byte, bit);
+            let request = FileRequest::Fid(1, Some(mask.clone()), None);
+            assert_eq!(
+                request.encode().unwrap(),
+                format!("fid=1&fmask={}&amask=00000000", hex::encode(&bytes))
+            );
+            let mut field_iter = vec![value, "next"].into_iter();
+            let fresp = mask.decode_response(&mut field_iter).unwrap();
+            assert!(check(&fresp), "byte {} bit {}: {:?}", byte, bit, fresp);
+            assert_eq!(field_iter.next(), Some("next"), "byte {} bit {}", byte, bit);
+        }
+    }
+
+    #[test]
+    fn every_amask_bit_decodes_its_field() {
+        for &(byte, bit, value, check) in AMASK {
+            let bytes = single_bit(4, byte, bit);
+            let mask = AnimeMask::from_bytes(&bytes).unwrap();
+            assert_eq!(mask.to_bytes().to_vec(), bytes, "byte {} bit {}", byte, bit);
+            let request = FileRequest::Fid(1, None, Some(mask.clone()));
+            assert_eq!(
+                request.encode().unwrap(),
+                format!("fid=1&fmask=0000000000&amask={}", hex::encode(&bytes))
+            );
+            let mut field_iter = vec![value, "next"].into_iter();
+            let aresp = mask.decode_response(&mut field_iter).unwrap();
+            assert!(check(&aresp), "byte {} bit {}: {:?}", byte, bit, aresp);
+            assert_eq!(field_iter.next(), Some("next"), "byte {} bit {}", byte, bit);
+        }
+    }
+
+    #[test]
+    fn reserved_bits_are_never_requested() {
+        for &(byte, bit) in FMASK_RESERVED {
+            let mask = FileMask::from_bytes(&single_bit(5, byte, bit)).unwrap();
+            assert_eq!(mask.to_bytes(), [0u8; 5], "byte {} bit {}", byte, bit);
+        }
+        for &(byte, bit) in AMASK_RESERVED {
+            let mask = AnimeMask::from_bytes(&single_bit(4, byte, bit)).unwrap();
+            assert_eq!(mask.to_bytes(), [0u8; 4], "byte {} bit {}", byte, bit);
+        }
+        assert_eq!(FileMask::from_bytes(&[0xff; 5]).unwrap().to_bytes(), FileMask::all().to_bytes());
+        assert_eq!(AnimeMask::from_bytes(&[0xff; 4]).unwrap().to_bytes(), AnimeMask::all().to_bytes());
+        assert!(FileMask::from_bytes(&[0xff; 4]).is_err());
+    }
+
+    #[test]
+    fn all_bits_decode_in_order() {
+        let values: Vec<&str> = FMASK.iter().map(|row| row.2)
+            .chain(AMASK.iter().map(|row| row.2))
+            .collect();
+        let request = FileRequest::Fid(1, Some(FileMask::all()), Some(AnimeMask::all()));
+        let (fresp, aresp) = decode(&request, &format!("1|{}\n", values.join("|")));
+        let (fresp, aresp) = (fresp.unwrap(), aresp.unwrap());
+        for &(byte, bit, _, check) in FMASK {
+            assert!(check(&fresp), "fmask byte {} bit {}", byte, bit);
+        }
+        for &(byte, bit, _, check) in AMASK {
+            assert!(check(&aresp), "amask byte {} bit {}", byte, bit);
+        }
+        assert_eq!(
+            aresp.relations,
+            Some(vec![(2, RelationType::Sequel), (3, RelationType::Prequel)])
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let values: Vec<&str> = FMASK.iter().map(|row| row.2)
+            .chain(AMASK.iter().map(|row| row.2))
+            .collect();
+        let request = FileRequest::Fid(1, Some(FileMask::all()), Some(AnimeMask::all()));
+        let data = format!("1|{}\n", values.join("|"));
+        let response = request.decode_response("220", "FILE", &data).unwrap();
+        let json = serde_json::to_string(&response).unwrap();
+        assert!(json.contains(r#""epno":"S1""#), "{}", json);
+        assert_eq!(serde_json::from_str::<FileResponse>(&json).unwrap(), response);
+    }
+
+    #[test]
+    fn mask_set_operations() {
+        let mask = FileMask::hashes().union(&FileMask::mylist());
+        assert!(mask.is_superset(&FileMask::hashes()));
+        assert!(!FileMask::hashes().is_superset(&mask));
+        assert_eq!(mask.intersection(&FileMask::hashes()), FileMask::hashes());
+        assert!(FileMask::hashes().intersection(&FileMask::mylist()).is_empty());
+
+        let field: FileMaskField = "ed2k".parse().unwrap();
+        assert_eq!(field, FileMaskField::Ed2k);
+        assert!(!FileMask::hashes().without(field).contains(FileMaskField::Ed2k));
+        assert!(FileMask::none().with(field).contains(FileMaskField::Ed2k));
+        assert!("nope".parse::<FileMaskField>().is_err());
+        assert_eq!(
+            FileMask::hashes().fields().map(|f| f.to_string()).collect::<Vec<_>>(),
+            vec!["size", "ed2k", "md5", "sha1", "crc32"]
+        );
+    }
+
+    #[test]
+    fn reuses_superset_replies() {
+        let wide = FileRequest::Fid(
+            1,
+            Some(FileMask::hashes().with(FileMaskField::Aid)),
+            Some(AnimeMask::episode_titles())
+        );
+        let stored = wide.cache_key().unwrap();
+        let data = "1|7|1024|ed2k|md5|sha1|crc32|S2|name|romaji|kanji\n";
+
+        let narrow = FileRequest::Fid(1, Some(FileMask::none().with(FileMaskField::Ed2k)), None);
+        let reused = narrow.reuse_cached(&stored, "220", data).unwrap();
+        assert_eq!(reused, "1|ed2k");
+        let (fresp, aresp) = decode(&narrow, &reused);
+        assert_eq!(fresp.unwrap().ed2k.as_deref(), Some("ed2k"));
+        assert_eq!(aresp, None);
+
+        let amask = AnimeMask::none().with(AnimeMaskField::EpName);
+        let narrow = FileRequest::Fid(1, None, Some(amask));
+        assert_eq!(narrow.reuse_cached(&stored, "220", data).as_deref(), Some("1|name"));
+        assert_eq!(narrow.reuse_cached(&stored, "320", "").as_deref(), Some(""));
+
+        let wider = FileRequest::Fid(1, Some(FileMask::all()), None);
+        assert_eq!(wider.reuse_cached(&stored, "220", data), None);
+        assert_eq!(FileRequest::Fid(1, None, None).reuse_cached(&stored, "220", data), None);
+        // too few values for the stored masks
+        assert_eq!(narrow.reuse_cached(&stored, "220", "1|7|1024"), None);
+    }
+
+    #[test]
+    fn dynamic_field_access() {
+        let amask = AnimeMask::none().with(AnimeMaskField::Epno);
+        let request = FileRequest::Fid(1, Some(FileMask::hashes()), Some(amask));
+        let (fresp, aresp) = decode(&request, "1|1024|ed2k|md5|sha1|crc32|S2\n");
+        let fresp = fresp.unwrap();
+        assert_eq!(fresp.get(FileMaskField::Size), Some(FieldValue::Int(1024)));
+        assert_eq!(fresp.get(FileMaskField::Aid), None);
+        assert_eq!(
+            fresp.iter().map(|(field, _)| field).collect::<Vec<_>>(),
+            FileMask::hashes().fields().collect::<Vec<_>>()
+        );
+        let epno = aresp.unwrap().get("epno".parse().unwrap()).unwrap();
+        assert_eq!(epno, FieldValue::EpNoRange("S2".parse().unwrap()));
+        assert_eq!(epno.to_string(), "S2");
+    }
+
+    #[test]
+    fn decodes_multi_episode_files() {
+        let amask = AnimeMask::none().with(AnimeMaskField::Epno).with(AnimeMaskField::EpName);
+        let request = FileRequest::Fid(1, None, Some(amask));
+        let (_, aresp) = decode(&request, "1|1-2|Double Feature\n");
+        let aresp = aresp.unwrap();
+        let epno = aresp.epno.unwrap();
+        assert_eq!(epno, EpNoRange { start: EpNo::Regular(1), end: EpNo::Regular(2) });
+        assert!(epno.contains(&EpNo::Regular(2)));
+        assert_eq!(aresp.ep_name.as_deref(), Some("Double Feature"));
+    }
 }
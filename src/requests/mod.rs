@@ -14,16 +14,28 @@ use tokio::sync::{
     mpsc::error::SendError,
 };
 use crate::AniDbError;
+use crate::cache::CacheKey;
+use crate::ser;
 
 pub trait AniDbRequest: Serialize {
-    type Error: fmt::Debug + error::Error + From<AniDbError> + From<serde_urlencoded::ser::Error> + From<io::Error> + From<RecvError> + From<SendError<String>>;
+    type Error: fmt::Debug + error::Error + From<AniDbError> + From<ser::Error> + From<io::Error> + From<RecvError> + From<SendError<String>>;
     type Response;
     fn name() -> &'static str;
     fn requires_login() -> bool {
         true
     }
-    fn encode(&self) -> Result<String, serde_urlencoded::ser::Error> {
-        serde_urlencoded::to_string(self)
+    fn encode(&self) -> Result<String, ser::Error> {
+        ser::to_string(self)
+    }
+    fn cache_key(&self) -> Result<CacheKey, ser::Error> {
+        Ok(CacheKey::new(Self::name(), &self.encode()?, &[]))
+    }
+    /// Rebuilds the data of a cached reply fetched with the masks of `stored`
+    /// into what this request would have received, `None` if it can't be
+    /// reused. Only replies fetched with the exact same masks are reused by
+    /// default.
+    fn reuse_cached(&self, _stored: &CacheKey, _code: &str, _data: &str) -> Option<String> {
+        None
     }
     fn decode_response(
         &self,
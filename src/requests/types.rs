@@ -1,6 +1,11 @@
+use std::fmt;
+use std::str::FromStr;
+use serde::{Serialize, Serializer};
 use crate::mask::FieldDecoder;
 
-#[derive(Debug)]
+/// An episode number, ordered the way AniDB lists them: regular episodes
+/// first, then specials, credits, trailers, parodies and others.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum EpNo {
     Regular(i32),
     Special(i32),
@@ -21,20 +26,772 @@ impl EpNo {
             EpNo::Other(x) => *x
         }
     }
+
+    fn prefix(&self) -> &'static str {
+        match self {
+            EpNo::Regular(_) => "",
+            EpNo::Special(_) => "S",
+            EpNo::Credit(_) => "C",
+            EpNo::Trailer(_) => "T",
+            EpNo::Parody(_) => "P",
+            EpNo::Other(_) => "O",
+        }
+    }
+}
+
+impl fmt::Display for EpNo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.prefix(), self.epno())
+    }
+}
+
+impl FromStr for EpNo {
+    type Err = crate::AniDbError;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let input = input.trim();
+        let mut chars = input.chars();
+        let (variant, number): (fn(i32) -> EpNo, &str) = match chars.next() {
+            None => return Err(crate::AniDbError::DecodeError(
+                String::from("Empty episode number")
+            )),
+            Some('S') => (EpNo::Special, chars.as_str()),
+            Some('C') => (EpNo::Credit, chars.as_str()),
+            Some('T') => (EpNo::Trailer, chars.as_str()),
+            Some('P') => (EpNo::Parody, chars.as_str()),
+            Some('O') => (EpNo::Other, chars.as_str()),
+            Some(_) => (EpNo::Regular, input),
+        };
+        number.parse()
+            .map(variant)
+            .map_err(|_| crate::AniDbError::DecodeError(
+                format!("Invalid episode number: {}", input)
+            ))
+    }
+}
+
+impl Serialize for EpNo {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where S: Serializer {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> ::serde::Deserialize<'de> for EpNo {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where D: ::serde::Deserializer<'de> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(::serde::de::Error::custom)
+    }
 }
 
 impl FieldDecoder for EpNo {
-    fn decode_field<'a>(
-        input: &'a str
+    fn decode_field(
+        input: &str
     ) -> Result<Self, crate::AniDbError>
     where Self: Sized {
-        match &input[..1] {
-            "S" => Ok(EpNo::Special(input[1..].parse()?)),
-            "C" => Ok(EpNo::Credit(input[1..].parse()?)),
-            "T" => Ok(EpNo::Trailer(input[1..].parse()?)),
-            "P" => Ok(EpNo::Parody(input[1..].parse()?)),
-            "O" => Ok(EpNo::Other(input[1..].parse()?)),
-            _ => Ok(EpNo::Regular(input.parse()?))
+        input.parse()
+    }
+}
+
+/// An inclusive range of episodes, e.g. `1-2` for a file containing two
+/// episodes. A single episode is a range with equal start and end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct EpNoRange {
+    pub start: EpNo,
+    pub end: EpNo,
+}
+
+impl EpNoRange {
+    pub fn contains(&self, epno: &EpNo) -> bool {
+        &self.start <= epno && epno <= &self.end
+    }
+}
+
+impl fmt::Display for EpNoRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+}
+
+impl FromStr for EpNoRange {
+    type Err = crate::AniDbError;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let mut parts = input.splitn(2, '-');
+        let start: EpNo = parts.next().unwrap_or("").parse()?;
+        let end = match parts.next() {
+            Some(end) => end.parse()?,
+            None => start,
+        };
+        if end < start {
+            return Err(crate::AniDbError::DecodeError(
+                format!("Invalid episode range: {}", input)
+            ));
+        }
+        Ok(EpNoRange { start, end })
+    }
+}
+
+impl Serialize for EpNoRange {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where S: Serializer {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> ::serde::Deserialize<'de> for EpNoRange {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where D: ::serde::Deserializer<'de> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(::serde::de::Error::custom)
+    }
+}
+
+impl FieldDecoder for EpNoRange {
+    fn decode_field(input: &str) -> Result<Self, crate::AniDbError>
+    where Self: Sized {
+        input.parse()
+    }
+}
+
+/// A point in time sent by AniDB as seconds since the unix epoch.
+/// AniDB uses `0` for dates it doesn't know.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub struct Timestamp(pub i64);
+
+impl Timestamp {
+    pub fn as_secs(&self) -> i64 {
+        self.0
+    }
+
+    pub fn is_unknown(&self) -> bool {
+        self.0 == 0
+    }
+
+    #[cfg(feature = "chrono")]
+    pub fn to_datetime(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        if self.is_unknown() {
+            return None;
         }
+        chrono::DateTime::from_timestamp(self.0, 0)
+    }
+}
+
+impl FieldDecoder for Timestamp {
+    fn decode_field(input: &str) -> Result<Self, crate::AniDbError>
+    where Self: Sized {
+        Ok(Timestamp(input.parse()?))
+    }
+}
+
+/// A calendar date where any part may be unknown, e.g. an anime announced
+/// for "2022" or "April 2022".
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub struct PartialDate {
+    pub year: Option<i32>,
+    pub month: Option<u32>,
+    pub day: Option<u32>,
+}
+
+impl PartialDate {
+    /// Converts a unix timestamp into a (UTC) calendar date, `0` is treated
+    /// as a completely unknown date.
+    pub fn from_timestamp(secs: i64) -> PartialDate {
+        if secs == 0 {
+            return PartialDate::default();
+        }
+        // https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+        let days = secs.div_euclid(86_400);
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z.rem_euclid(146_097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+        PartialDate {
+            year: Some(year as i32),
+            month: Some(month as u32),
+            day: Some(day as u32),
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.year.is_some() && self.month.is_some() && self.day.is_some()
+    }
+
+    pub fn is_unknown(&self) -> bool {
+        self.year.is_none() && self.month.is_none() && self.day.is_none()
+    }
+
+    fn forget_day(&mut self) {
+        self.day = None;
+    }
+
+    fn forget_month(&mut self) {
+        self.month = None;
+        self.day = None;
+    }
+
+    fn forget_year(&mut self) {
+        self.year = None;
+    }
+
+    #[cfg(feature = "chrono")]
+    pub fn to_naive_date(&self) -> Option<chrono::NaiveDate> {
+        chrono::NaiveDate::from_ymd_opt(self.year?, self.month?, self.day?)
+    }
+}
+
+/// Formats as `YYYY-MM-DD`, leaving out unknown trailing parts and using
+/// `?` for an unknown year.
+impl fmt::Display for PartialDate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.year {
+            Some(year) => write!(f, "{:04}", year)?,
+            None => f.write_str("?")?,
+        }
+        if let Some(month) = self.month {
+            write!(f, "-{:02}", month)?;
+            if let Some(day) = self.day {
+                write!(f, "-{:02}", day)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FieldDecoder for PartialDate {
+    fn decode_field(input: &str) -> Result<Self, crate::AniDbError>
+    where Self: Sized {
+        Ok(PartialDate::from_timestamp(input.parse()?))
+    }
+}
+
+/// The `dateflags` field of an ANIME reply, describing which parts of
+/// `air_date` and `end_date` are actually known.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub struct DateFlags(pub u8);
+
+impl DateFlags {
+    pub fn start_day_unknown(&self) -> bool {
+        self.0 & 1 != 0
+    }
+
+    pub fn start_month_unknown(&self) -> bool {
+        self.0 & 2 != 0
+    }
+
+    pub fn end_day_unknown(&self) -> bool {
+        self.0 & 4 != 0
+    }
+
+    pub fn end_month_unknown(&self) -> bool {
+        self.0 & 8 != 0
+    }
+
+    pub fn ended(&self) -> bool {
+        self.0 & 16 != 0
+    }
+
+    pub fn start_year_unknown(&self) -> bool {
+        self.0 & 32 != 0
+    }
+
+    pub fn end_year_unknown(&self) -> bool {
+        self.0 & 64 != 0
+    }
+
+    pub(crate) fn apply_to_start(&self, date: &mut PartialDate) {
+        if self.start_day_unknown() {
+            date.forget_day();
+        }
+        if self.start_month_unknown() {
+            date.forget_month();
+        }
+        if self.start_year_unknown() {
+            date.forget_year();
+        }
+    }
+
+    pub(crate) fn apply_to_end(&self, date: &mut PartialDate) {
+        if self.end_day_unknown() {
+            date.forget_day();
+        }
+        if self.end_month_unknown() {
+            date.forget_month();
+        }
+        if self.end_year_unknown() {
+            date.forget_year();
+        }
+    }
+}
+
+impl FieldDecoder for DateFlags {
+    fn decode_field(input: &str) -> Result<Self, crate::AniDbError>
+    where Self: Sized {
+        Ok(DateFlags(input.parse()?))
+    }
+}
+
+/// The `year` field, e.g. `2021`, `2019-2021` or `2021-?` for a show that
+/// hasn't finished airing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub struct YearRange {
+    pub start: Option<i32>,
+    pub end: Option<i32>,
+}
+
+impl fmt::Display for YearRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn year(f: &mut fmt::Formatter<'_>, year: Option<i32>) -> fmt::Result {
+            match year {
+                Some(year) => write!(f, "{}", year),
+                None => f.write_str("?"),
+            }
+        }
+        year(f, self.start)?;
+        if self.end != self.start {
+            f.write_str("-")?;
+            year(f, self.end)?;
+        }
+        Ok(())
+    }
+}
+
+impl FieldDecoder for YearRange {
+    fn decode_field(input: &str) -> Result<Self, crate::AniDbError>
+    where Self: Sized {
+        fn year(part: &str) -> Result<Option<i32>, crate::AniDbError> {
+            match part.trim() {
+                "" | "?" => Ok(None),
+                part => Ok(Some(part.parse()?)),
+            }
+        }
+        let mut parts = input.splitn(2, '-');
+        let start = year(parts.next().unwrap_or(""))?;
+        let end = match parts.next() {
+            Some(end) => year(end)?,
+            None => start,
+        };
+        Ok(YearRange { start, end })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub enum CrcStatus {
+    Unchecked,
+    Ok,
+    Mismatch,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub enum Censorship {
+    Unknown,
+    Censored,
+    Uncensored,
+}
+
+/// The `state` bitfield of a FILE reply.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub struct FileState(pub u16);
+
+impl FileState {
+    const CRC_OK: u16 = 1;
+    const CRC_ERR: u16 = 2;
+    const IS_V2: u16 = 4;
+    const IS_V3: u16 = 8;
+    const IS_V4: u16 = 16;
+    const IS_V5: u16 = 32;
+    const UNCENSORED: u16 = 64;
+    const CENSORED: u16 = 128;
+
+    pub fn bits(&self) -> u16 {
+        self.0
+    }
+
+    pub fn crc_status(&self) -> CrcStatus {
+        if self.0 & FileState::CRC_ERR != 0 {
+            CrcStatus::Mismatch
+        } else if self.0 & FileState::CRC_OK != 0 {
+            CrcStatus::Ok
+        } else {
+            CrcStatus::Unchecked
+        }
+    }
+
+    /// The release version of the file, `1` unless a later version is flagged.
+    pub fn version(&self) -> u8 {
+        if self.0 & FileState::IS_V5 != 0 {
+            5
+        } else if self.0 & FileState::IS_V4 != 0 {
+            4
+        } else if self.0 & FileState::IS_V3 != 0 {
+            3
+        } else if self.0 & FileState::IS_V2 != 0 {
+            2
+        } else {
+            1
+        }
+    }
+
+    pub fn censorship(&self) -> Censorship {
+        if self.0 & FileState::CENSORED != 0 {
+            Censorship::Censored
+        } else if self.0 & FileState::UNCENSORED != 0 {
+            Censorship::Uncensored
+        } else {
+            Censorship::Unknown
+        }
+    }
+}
+
+impl FieldDecoder for FileState {
+    fn decode_field(input: &str) -> Result<Self, crate::AniDbError>
+    where Self: Sized {
+        Ok(FileState(input.parse()?))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub enum AnimeType {
+    TvSeries,
+    Ova,
+    Movie,
+    Other,
+    Web,
+    TvSpecial,
+    MusicVideo,
+    Unknown,
+}
+
+impl fmt::Display for AnimeType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AnimeType::TvSeries => "TV Series",
+            AnimeType::Ova => "OVA",
+            AnimeType::Movie => "Movie",
+            AnimeType::Other => "Other",
+            AnimeType::Web => "Web",
+            AnimeType::TvSpecial => "TV Special",
+            AnimeType::MusicVideo => "Music Video",
+            AnimeType::Unknown => "unknown",
+        })
+    }
+}
+
+impl FieldDecoder for AnimeType {
+    fn decode_field(input: &str) -> Result<Self, crate::AniDbError>
+    where Self: Sized {
+        Ok(match input {
+            "TV Series" => AnimeType::TvSeries,
+            "OVA" => AnimeType::Ova,
+            "Movie" => AnimeType::Movie,
+            "Other" => AnimeType::Other,
+            "Web" => AnimeType::Web,
+            "TV Special" => AnimeType::TvSpecial,
+            "Music Video" => AnimeType::MusicVideo,
+            _ => AnimeType::Unknown,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub enum RelationType {
+    Sequel,
+    Prequel,
+    SameSetting,
+    AlternativeSetting,
+    AlternativeVersion,
+    MusicVideo,
+    Character,
+    SideStory,
+    ParentStory,
+    Summary,
+    FullStory,
+    Other,
+}
+
+impl fmt::Display for RelationType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RelationType::Sequel => "sequel",
+            RelationType::Prequel => "prequel",
+            RelationType::SameSetting => "same setting",
+            RelationType::AlternativeSetting => "alternative setting",
+            RelationType::AlternativeVersion => "alternative version",
+            RelationType::MusicVideo => "music video",
+            RelationType::Character => "character",
+            RelationType::SideStory => "side story",
+            RelationType::ParentStory => "parent story",
+            RelationType::Summary => "summary",
+            RelationType::FullStory => "full story",
+            RelationType::Other => "other",
+        })
+    }
+}
+
+impl FieldDecoder for RelationType {
+    fn decode_field(input: &str) -> Result<Self, crate::AniDbError>
+    where Self: Sized {
+        Ok(match input.parse()? {
+            1 => RelationType::Sequel,
+            2 => RelationType::Prequel,
+            11 => RelationType::SameSetting,
+            12 => RelationType::AlternativeSetting,
+            32 => RelationType::AlternativeVersion,
+            41 => RelationType::MusicVideo,
+            42 => RelationType::Character,
+            51 => RelationType::SideStory,
+            52 => RelationType::ParentStory,
+            61 => RelationType::Summary,
+            62 => RelationType::FullStory,
+            _ => RelationType::Other,
+        })
+    }
+}
+
+/// Pairs up the parallel `related_aid_list` and `related_aid_type` fields.
+pub(crate) fn zip_relations(
+    aids: &Option<Vec<i32>>,
+    types: &Option<Vec<RelationType>>
+) -> Result<Option<Vec<(i32, RelationType)>>, crate::AniDbError> {
+    match (aids, types) {
+        (Some(aids), Some(types)) => {
+            if aids.len() != types.len() {
+                return Err(crate::AniDbError::DecodeError(String::from(
+                    "related_aid_list and related_aid_type differ in length"
+                )));
+            }
+            Ok(Some(aids.iter().copied().zip(types.iter().copied()).collect()))
+        },
+        _ => Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(year: i32, month: u32, day: u32) -> PartialDate {
+        PartialDate {
+            year: Some(year),
+            month: Some(month),
+            day: Some(day),
+        }
+    }
+
+    #[test]
+    fn civil_from_days() {
+        assert_eq!(PartialDate::from_timestamp(1_625_529_600), date(2021, 7, 6));
+        // any time during the day
+        assert_eq!(PartialDate::from_timestamp(1_625_529_600 + 86_399), date(2021, 7, 6));
+        assert_eq!(PartialDate::from_timestamp(1), date(1970, 1, 1));
+        assert_eq!(PartialDate::from_timestamp(-86_400), date(1969, 12, 31));
+        assert_eq!(PartialDate::from_timestamp(-1), date(1969, 12, 31));
+        assert_eq!(PartialDate::from_timestamp(-310_521_600), date(1960, 2, 29));
+        assert_eq!(PartialDate::from_timestamp(951_782_400), date(2000, 2, 29));
+        assert_eq!(PartialDate::from_timestamp(1_582_934_400), date(2020, 2, 29));
+        // 1900 is not a leap year
+        assert_eq!(PartialDate::from_timestamp(-2_203_977_600), date(1900, 2, 28));
+        assert_eq!(PartialDate::from_timestamp(-2_203_891_200), date(1900, 3, 1));
+    }
+
+    #[test]
+    fn unknown_timestamps() {
+        assert!(Timestamp(0).is_unknown());
+        assert!(!Timestamp(1).is_unknown());
+        assert!(PartialDate::from_timestamp(0).is_unknown());
+        assert_eq!(PartialDate::from_timestamp(0).to_string(), "?");
+        assert_eq!(<Timestamp as FieldDecoder>::decode_field("0").unwrap(), Timestamp(0));
+        assert!(<Timestamp as FieldDecoder>::decode_field("soon").is_err());
+    }
+
+    #[test]
+    fn dateflags_mask_parts() {
+        // (flags, start, end) for 2021-07-06 to 2021-09-21
+        let cases = [
+            (0, "2021-07-06", "2021-09-21"),
+            (1, "2021-07", "2021-09-21"),
+            (2, "2021", "2021-09-21"),
+            (4, "2021-07-06", "2021-09"),
+            (8, "2021-07-06", "2021"),
+            (16, "2021-07-06", "2021-09-21"),
+            (32, "?-07-06", "2021-09-21"),
+            (64, "2021-07-06", "?-09-21"),
+            (1 | 2 | 32, "?", "2021-09-21"),
+        ];
+        for (flags, start, end) in cases {
+            let (mut air_date, mut end_date) = (date(2021, 7, 6), date(2021, 9, 21));
+            DateFlags(flags).apply_to_start(&mut air_date);
+            DateFlags(flags).apply_to_end(&mut end_date);
+            assert_eq!(air_date.to_string(), start, "flags {}", flags);
+            assert_eq!(end_date.to_string(), end, "flags {}", flags);
+        }
+        assert!(DateFlags(16).ended());
+        assert!(!DateFlags(15).ended());
+    }
+
+    #[test]
+    fn year_ranges() {
+        let decode = <YearRange as FieldDecoder>::decode_field;
+        assert_eq!(decode("2021").unwrap(), YearRange { start: Some(2021), end: Some(2021) });
+        assert_eq!(decode("2019-2021").unwrap(), YearRange { start: Some(2019), end: Some(2021) });
+        assert_eq!(decode("2021-?").unwrap(), YearRange { start: Some(2021), end: None });
+        for input in ["2021", "2019-2021", "2021-?"] {
+            assert_eq!(decode(input).unwrap().to_string(), input);
+        }
+        assert!(decode("20x1").is_err());
+        assert!(decode("2019-soon").is_err());
+    }
+
+    #[test]
+    fn file_state_bits() {
+        // (state, crc status, version, censorship) for the documented bits
+        let cases = [
+            (0, CrcStatus::Unchecked, 1, Censorship::Unknown),
+            (1, CrcStatus::Ok, 1, Censorship::Unknown),
+            (2, CrcStatus::Mismatch, 1, Censorship::Unknown),
+            // conflicting flags, a failed check wins
+            (1 | 2, CrcStatus::Mismatch, 1, Censorship::Unknown),
+            (4, CrcStatus::Unchecked, 2, Censorship::Unknown),
+            (8, CrcStatus::Unchecked, 3, Censorship::Unknown),
+            (16, CrcStatus::Unchecked, 4, Censorship::Unknown),
+            (32, CrcStatus::Unchecked, 5, Censorship::Unknown),
+            // the highest flagged version wins
+            (4 | 32, CrcStatus::Unchecked, 5, Censorship::Unknown),
+            (64, CrcStatus::Unchecked, 1, Censorship::Uncensored),
+            (128, CrcStatus::Unchecked, 1, Censorship::Censored),
+            (1 | 8 | 64, CrcStatus::Ok, 3, Censorship::Uncensored),
+        ];
+        for (bits, crc, version, censorship) in cases {
+            let state = FileState(bits);
+            assert_eq!(state.crc_status(), crc, "state {}", bits);
+            assert_eq!(state.version(), version, "state {}", bits);
+            assert_eq!(state.censorship(), censorship, "state {}", bits);
+        }
+        assert_eq!(<FileState as FieldDecoder>::decode_field("73").unwrap().bits(), 73);
+    }
+
+    #[test]
+    fn anime_types() {
+        let types = [
+            ("TV Series", AnimeType::TvSeries),
+            ("OVA", AnimeType::Ova),
+            ("Movie", AnimeType::Movie),
+            ("Other", AnimeType::Other),
+            ("Web", AnimeType::Web),
+            ("TV Special", AnimeType::TvSpecial),
+            ("Music Video", AnimeType::MusicVideo),
+        ];
+        for (input, ty) in types {
+            assert_eq!(<AnimeType as FieldDecoder>::decode_field(input).unwrap(), ty);
+            assert_eq!(ty.to_string(), input);
+        }
+        for input in ["", "tv series", "Radio"] {
+            assert_eq!(<AnimeType as FieldDecoder>::decode_field(input).unwrap(), AnimeType::Unknown);
+        }
+    }
+
+    #[test]
+    fn relation_types() {
+        let relations = [
+            ("1", RelationType::Sequel),
+            ("2", RelationType::Prequel),
+            ("11", RelationType::SameSetting),
+            ("12", RelationType::AlternativeSetting),
+            ("32", RelationType::AlternativeVersion),
+            ("41", RelationType::MusicVideo),
+            ("42", RelationType::Character),
+            ("51", RelationType::SideStory),
+            ("52", RelationType::ParentStory),
+            ("61", RelationType::Summary),
+            ("62", RelationType::FullStory),
+            ("100", RelationType::Other),
+            ("3", RelationType::Other),
+        ];
+        for (input, relation) in relations {
+            assert_eq!(<RelationType as FieldDecoder>::decode_field(input).unwrap(), relation);
+        }
+        assert!(<RelationType as FieldDecoder>::decode_field("sequel").is_err());
+    }
+
+    #[test]
+    fn zips_relations() {
+        let aids = Some(vec![2, 3]);
+        let types = Some(vec![RelationType::Sequel, RelationType::Prequel]);
+        assert_eq!(
+            zip_relations(&aids, &types).unwrap(),
+            Some(vec![(2, RelationType::Sequel), (3, RelationType::Prequel)])
+        );
+        assert_eq!(zip_relations(&aids, &None).unwrap(), None);
+        assert_eq!(zip_relations(&Some(vec![]), &Some(vec![])).unwrap(), Some(vec![]));
+        assert!(zip_relations(&Some(vec![2]), &types).is_err());
+    }
+
+    #[test]
+    fn episode_order() {
+        let ordered = ["1", "12", "S1", "S2", "C1", "T1", "P1", "O1"];
+        let parsed: Vec<EpNo> = ordered.iter().map(|epno| epno.parse().unwrap()).collect();
+        assert!(parsed.windows(2).all(|w| w[0] < w[1]), "{:?}", parsed);
+        let mut shuffled = parsed.clone();
+        shuffled.reverse();
+        shuffled.sort();
+        assert_eq!(shuffled, parsed);
+    }
+
+    #[test]
+    fn episode_round_trip() {
+        for input in ["1", "13", "S1", "C2", "T3", "P4", "O5"] {
+            assert_eq!(input.parse::<EpNo>().unwrap().to_string(), input);
+        }
+        assert_eq!("S1".parse::<EpNo>().unwrap(), EpNo::Special(1));
+        assert_eq!("C2".parse::<EpNo>().unwrap(), EpNo::Credit(2));
+        for input in ["1", "1-2", "S1-S3"] {
+            assert_eq!(input.parse::<EpNoRange>().unwrap().to_string(), input);
+        }
+        assert_eq!("3-3".parse::<EpNoRange>().unwrap().to_string(), "3");
+    }
+
+    #[test]
+    fn malformed_episodes() {
+        for input in ["", "S", "Sx", "x1", "1.5"] {
+            assert!(input.parse::<EpNo>().is_err(), "{:?}", input);
+            assert!(<EpNo as FieldDecoder>::decode_field(input).is_err(), "{:?}", input);
+        }
+        for input in ["", "1-", "-2", "2-1", "S1-1"] {
+            assert!(input.parse::<EpNoRange>().is_err(), "{:?}", input);
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn episode_serde() {
+        let epno = EpNo::Credit(2);
+        assert_eq!(serde_json::to_string(&epno).unwrap(), r#""C2""#);
+        assert_eq!(serde_json::from_str::<EpNo>(r#""C2""#).unwrap(), epno);
+        let range: EpNoRange = "S1-S3".parse().unwrap();
+        assert_eq!(serde_json::to_string(&range).unwrap(), r#""S1-S3""#);
+        assert_eq!(serde_json::from_str::<EpNoRange>(r#""S1-S3""#).unwrap(), range);
+        assert!(serde_json::from_str::<EpNo>(r#""Sx""#).is_err());
+        assert!(serde_json::from_str::<EpNoRange>("1").is_err());
     }
 }
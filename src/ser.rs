@@ -0,0 +1,409 @@
+use std::fmt;
+use serde::ser::{self, Impossible, Serialize};
+
+// AniDB arguments are plain `key=value` pairs joined by `&`. Values are not
+// percent-decoded by the server, the only escaping it understands is `&` as
+// `&amp;` and line breaks as `<br />`.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error(String);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error(msg.to_string())
+    }
+}
+
+fn unsupported(what: &str) -> Error {
+    Error(format!("{} cannot be encoded as an AniDB argument", what))
+}
+
+pub fn escape(input: &str) -> String {
+    input.replace('&', "&amp;")
+        .replace("\r\n", "<br />")
+        .replace('\n', "<br />")
+}
+
+pub fn to_string<T>(input: &T) -> Result<String, Error>
+where T: Serialize + ?Sized {
+    let mut serializer = ArgsSerializer {
+        output: String::new(),
+        key: None,
+    };
+    input.serialize(&mut serializer)?;
+    Ok(serializer.output)
+}
+
+struct ArgsSerializer {
+    output: String,
+    key: Option<String>,
+}
+
+impl ArgsSerializer {
+    fn push_pair(&mut self, key: &str, value: Option<String>) {
+        // `None` values are skipped entirely rather than sent empty
+        if let Some(value) = value {
+            if !self.output.is_empty() {
+                self.output.push('&');
+            }
+            self.output.push_str(key);
+            self.output.push('=');
+            self.output.push_str(&value);
+        }
+    }
+}
+
+macro_rules! unsupported_top_level {
+    ($($method:ident($($ty:ty),*)),*) => {
+        $(
+            fn $method(self, $(_: $ty),*) -> Result<Self::Ok, Self::Error> {
+                Err(unsupported("top level value"))
+            }
+        )*
+    }
+}
+
+impl ser::Serializer for &mut ArgsSerializer {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = Impossible<(), Error>;
+    type SerializeTuple = Impossible<(), Error>;
+    type SerializeTupleStruct = Impossible<(), Error>;
+    type SerializeTupleVariant = Impossible<(), Error>;
+    type SerializeMap = Self;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Impossible<(), Error>;
+
+    unsupported_top_level!(
+        serialize_bool(bool),
+        serialize_i8(i8),
+        serialize_i16(i16),
+        serialize_i32(i32),
+        serialize_i64(i64),
+        serialize_u8(u8),
+        serialize_u16(u16),
+        serialize_u32(u32),
+        serialize_u64(u64),
+        serialize_f32(f32),
+        serialize_f64(f64),
+        serialize_char(char),
+        serialize_str(&str),
+        serialize_bytes(&[u8]),
+        serialize_none(),
+        serialize_unit(),
+        serialize_unit_struct(&'static str),
+        serialize_unit_variant(&'static str, u32, &'static str)
+    );
+
+    fn serialize_some<T>(self, value: &T) -> Result<(), Error>
+    where T: Serialize + ?Sized {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_struct<T>(self, _name: &'static str, value: &T) -> Result<(), Error>
+    where T: Serialize + ?Sized {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T
+    ) -> Result<(), Error>
+    where T: Serialize + ?Sized {
+        Err(unsupported("top level value"))
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, Error> {
+        Err(unsupported("top level sequence"))
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple, Error> {
+        Err(unsupported("top level tuple"))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize
+    ) -> Result<Self::SerializeTupleStruct, Error> {
+        Err(unsupported("top level tuple"))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize
+    ) -> Result<Self::SerializeTupleVariant, Error> {
+        Err(unsupported("top level tuple"))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, Error> {
+        Ok(self)
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        _len: usize
+    ) -> Result<Self::SerializeStruct, Error> {
+        Ok(self)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize
+    ) -> Result<Self::SerializeStructVariant, Error> {
+        Err(unsupported("top level struct variant"))
+    }
+}
+
+impl ser::SerializeMap for &mut ArgsSerializer {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T>(&mut self, key: &T) -> Result<(), Error>
+    where T: Serialize + ?Sized {
+        let key = key.serialize(ValueSerializer)?
+            .ok_or_else(|| unsupported("empty key"))?;
+        self.key = Some(key);
+        Ok(())
+    }
+
+    fn serialize_value<T>(&mut self, value: &T) -> Result<(), Error>
+    where T: Serialize + ?Sized {
+        let key = self.key.take()
+            .ok_or_else(|| Error(String::from("value serialized before key")))?;
+        let value = value.serialize(ValueSerializer)?;
+        self.push_pair(&key, value);
+        Ok(())
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeStruct for &mut ArgsSerializer {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<(), Error>
+    where T: Serialize + ?Sized {
+        let value = value.serialize(ValueSerializer)?;
+        self.push_pair(key, value);
+        Ok(())
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Serializes a single argument value, `None` means the argument is omitted.
+struct ValueSerializer;
+
+macro_rules! serialize_display {
+    ($($method:ident($ty:ty)),*) => {
+        $(
+            fn $method(self, v: $ty) -> Result<Self::Ok, Self::Error> {
+                Ok(Some(v.to_string()))
+            }
+        )*
+    }
+}
+
+impl ser::Serializer for ValueSerializer {
+    type Ok = Option<String>;
+    type Error = Error;
+    type SerializeSeq = Impossible<Option<String>, Error>;
+    type SerializeTuple = Impossible<Option<String>, Error>;
+    type SerializeTupleStruct = Impossible<Option<String>, Error>;
+    type SerializeTupleVariant = Impossible<Option<String>, Error>;
+    type SerializeMap = Impossible<Option<String>, Error>;
+    type SerializeStruct = Impossible<Option<String>, Error>;
+    type SerializeStructVariant = Impossible<Option<String>, Error>;
+
+    serialize_display!(
+        serialize_i8(i8),
+        serialize_i16(i16),
+        serialize_i32(i32),
+        serialize_i64(i64),
+        serialize_u8(u8),
+        serialize_u16(u16),
+        serialize_u32(u32),
+        serialize_u64(u64),
+        serialize_f32(f32),
+        serialize_f64(f64)
+    );
+
+    fn serialize_bool(self, v: bool) -> Result<Self::Ok, Error> {
+        Ok(Some(String::from(if v { "1" } else { "0" })))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Self::Ok, Error> {
+        self.serialize_str(v.encode_utf8(&mut [0u8; 4]))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Self::Ok, Error> {
+        Ok(Some(escape(v)))
+    }
+
+    fn serialize_bytes(self, _v: &[u8]) -> Result<Self::Ok, Error> {
+        Err(unsupported("bytes"))
+    }
+
+    fn serialize_none(self) -> Result<Self::Ok, Error> {
+        Ok(None)
+    }
+
+    fn serialize_some<T>(self, value: &T) -> Result<Self::Ok, Error>
+    where T: Serialize + ?Sized {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Self::Ok, Error> {
+        Ok(None)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Self::Ok, Error> {
+        Ok(None)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str
+    ) -> Result<Self::Ok, Error> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T>(self, _name: &'static str, value: &T) -> Result<Self::Ok, Error>
+    where T: Serialize + ?Sized {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T
+    ) -> Result<Self::Ok, Error>
+    where T: Serialize + ?Sized {
+        Err(unsupported("enum with data"))
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, Error> {
+        Err(unsupported("sequence"))
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple, Error> {
+        Err(unsupported("tuple"))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize
+    ) -> Result<Self::SerializeTupleStruct, Error> {
+        Err(unsupported("tuple"))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize
+    ) -> Result<Self::SerializeTupleVariant, Error> {
+        Err(unsupported("enum with data"))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, Error> {
+        Err(unsupported("nested map"))
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        _len: usize
+    ) -> Result<Self::SerializeStruct, Error> {
+        Err(unsupported("nested struct"))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize
+    ) -> Result<Self::SerializeStructVariant, Error> {
+        Err(unsupported("enum with data"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::requests::AniDbRequest;
+    use crate::{AnimeRequest, AuthRequest};
+
+    fn encode_name(name: &str) -> String {
+        AnimeRequest::from_anime_name(name, None).encode().unwrap()
+    }
+
+    #[test]
+    fn escapes_ampersand() {
+        assert_eq!(encode_name("Kill la Kill & more"), "aname=Kill la Kill &amp; more");
+        assert_eq!(encode_name("&amp;"), "aname=&amp;amp;");
+    }
+
+    #[test]
+    fn keeps_equals_and_spaces() {
+        assert_eq!(encode_name("x = y"), "aname=x = y");
+    }
+
+    #[test]
+    fn keeps_non_ascii() {
+        assert_eq!(
+            encode_name("転生したらスライムだった件"),
+            "aname=転生したらスライムだった件"
+        );
+    }
+
+    #[test]
+    fn escapes_newlines() {
+        assert_eq!(encode_name("a\nb\r\nc"), "aname=a<br />b<br />c");
+    }
+
+    #[test]
+    fn encodes_auth_request() {
+        let auth = AuthRequest::builder()
+            .user(String::from("user"))
+            .pass(String::from("p&ss=word"))
+            .client(String::from("client"))
+            .clientver(1)
+            .nat(true)
+            .build();
+        assert_eq!(
+            auth.encode().unwrap(),
+            "user=user&pass=p&amp;ss=word&protover=3&client=client&clientver=1&nat=1&enc=UTF8"
+        );
+    }
+}